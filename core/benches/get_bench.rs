@@ -8,7 +8,7 @@ fn setup() -> core::hydradb::HydraDB {
     // 100,000 * 32 bytes ≈ 3.2 MB
     let file_size = 3_200_000;
 
-    let mut db = HydraDBBuilder::new()
+    let db = HydraDBBuilder::new()
         .with_cask("get_bench_data")
         .with_file_limit(file_size)
         .build()
@@ -26,7 +26,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     group.sample_size(10);
     group.bench_function("get 100k entries", |b| {
         b.iter_batched(
-            || setup(),
+            setup,
             |db| {
                 let mut rng = rand::rng();
 
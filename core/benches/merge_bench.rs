@@ -8,7 +8,7 @@ fn setup() -> core::hydradb::HydraDB {
     let entry_per_file = 100_000;
     let file_size = 3_200_000;
 
-    let mut db = HydraDBBuilder::new()
+    let db = HydraDBBuilder::new()
         .with_cask("merge_bench_data")
         .with_file_limit(file_size)
         .build()
@@ -32,11 +32,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("merge_operations");
     group.sample_size(10);
     group.bench_function("merge 1 million entries", |b| {
-        b.iter_batched(
-            || setup(),
-            |db| db.merge(),
-            criterion::BatchSize::LargeInput,
-        )
+        b.iter_batched(setup, |db| db.merge(), criterion::BatchSize::LargeInput)
     });
     group.finish();
 
@@ -19,7 +19,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     group.sample_size(10);
     group.bench_function("put 1 million entries", |b| {
         b.iter_batched(
-            || setup(),
+            setup,
             |db| {
                 let entry_per_file = 100_000;
                 for i in 0..10 {
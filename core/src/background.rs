@@ -0,0 +1,71 @@
+use crate::hydradb::HydraDB;
+use anyhow::Result;
+use log::warn;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// background thread that runs `job` on the db every `interval`, like compaction or
+/// keydir snapshots. it only holds a weak reference so that it doesn't keep the db alive
+#[derive(Debug)]
+pub(crate) struct BackgroundThread {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundThread {
+    /// `name` describes the job in the logs
+    pub fn spawn(
+        name: &'static str,
+        db: Weak<HydraDB>,
+        interval: Duration,
+        job: fn(&HydraDB) -> Result<()>,
+    ) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+
+        let handle = thread::spawn(move || {
+            let (lock, cv) = &*thread_stop;
+            loop {
+                {
+                    let stopped = lock.lock().unwrap();
+                    let (stopped, _) = cv
+                        .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+                        .unwrap();
+                    if *stopped {
+                        break;
+                    }
+                }
+
+                // the lock isn't held here as dropping the last reference to the db
+                // drops this thread's handle too
+                let Some(db) = db.upgrade() else {
+                    break;
+                };
+                if let Err(e) = job(&db) {
+                    warn!("background {name} failed: {e}");
+                }
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for BackgroundThread {
+    fn drop(&mut self) {
+        let (lock, cv) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cv.notify_one();
+
+        // the thread itself may drop the db when it holds the last reference to it
+        if let Some(handle) = self.handle.take()
+            && handle.thread().id() != thread::current().id()
+        {
+            let _ = handle.join();
+        }
+    }
+}
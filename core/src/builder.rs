@@ -1,11 +1,28 @@
+use crate::compaction::{
+    CompactionFilter, CompactionFilterHandle, CompactionPolicy, CompactionWindow,
+};
+use crate::durability::Durability;
 use crate::hydradb::HydraDB;
+use crate::key_dir::KeyDirKind;
 use anyhow::Result;
+use log::warn;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Default)]
 pub struct HydraDBBuilder {
     max_file_size_threshold: u64,
     cask: Option<String>,
     cache_size: usize,
+    durability: Durability,
+    compaction: CompactionPolicy,
+    merge_rate_limit: u64,
+    compaction_filter: CompactionFilterHandle,
+    keydir_snapshot: Option<Duration>,
+    restore_threads: usize,
+    restore_rate_limit: u64,
+    keydir: KeyDirKind,
 }
 
 impl HydraDBBuilder {
@@ -14,6 +31,14 @@ impl HydraDBBuilder {
             max_file_size_threshold: 1048576,
             cask: None,
             cache_size: 10,
+            durability: Durability::None,
+            compaction: CompactionPolicy::default(),
+            merge_rate_limit: 0,
+            compaction_filter: CompactionFilterHandle::default(),
+            keydir_snapshot: None,
+            restore_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            restore_rate_limit: 0,
+            keydir: KeyDirKind::Standard,
         }
     }
 
@@ -27,16 +52,139 @@ impl HydraDBBuilder {
         self
     }
 
+    /// sets when writes get synced to disk. defaults to `Durability::None`
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// merges in the background once dead records make up at least `ratio` of the
+    /// immutable files. needs `build_shared`
+    pub fn with_compaction_dead_ratio(mut self, ratio: f64) -> Self {
+        self.compaction.dead_ratio = Some(ratio);
+        self
+    }
+
+    /// merges in the background once the immutable files hold at least `bytes` dead
+    /// bytes. needs `build_shared`
+    pub fn with_compaction_dead_bytes(mut self, bytes: u64) -> Self {
+        self.compaction.dead_bytes = Some(bytes);
+        self
+    }
+
+    /// merges in the background once there are at least `n` immutable files.
+    /// needs `build_shared`
+    pub fn with_compaction_file_count(mut self, n: usize) -> Self {
+        self.compaction.file_count = Some(n);
+        self
+    }
+
+    /// makes background compaction merge only up to `n` of the most fragmented files.
+    /// files below the dead ratio threshold, if set, are left alone. needs `build_shared`
+    pub fn with_compaction_max_files(mut self, n: usize) -> Self {
+        self.compaction.max_files = Some(n);
+        self
+    }
+
+    /// only compacts in the background between the given hours of the day (utc).
+    /// needs `build_shared`
+    pub fn with_compaction_window(mut self, start_hour: u8, end_hour: u8) -> Self {
+        self.compaction.window = Some(CompactionWindow {
+            start_hour,
+            end_hour,
+        });
+        self
+    }
+
+    /// sets how often the compaction thresholds are checked. defaults to a minute.
+    /// needs `build_shared`
+    pub fn with_compaction_interval(mut self, interval: Duration) -> Self {
+        self.compaction.interval = interval;
+        self
+    }
+
+    /// has merges pass every live record through `filter`, which may drop it or
+    /// change its value
+    pub fn with_compaction_filter(mut self, filter: impl CompactionFilter + 'static) -> Self {
+        self.compaction_filter = CompactionFilterHandle::new(filter);
+        self
+    }
+
+    /// limits the i/o of merges to `bytes_per_sec`. unlimited by default
+    pub fn with_merge_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.merge_rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// persists the keydir on a clean shutdown & every `interval` so that opening the
+    /// db only replays the records written since. the periodic ones need `build_shared`
+    pub fn with_keydir_snapshot(mut self, interval: Duration) -> Self {
+        self.keydir_snapshot = Some(interval);
+        self
+    }
+
+    /// sets how many threads restore the keydir when the db is opened. defaults to
+    /// the number of cpus
+    pub fn with_restore_threads(mut self, n: usize) -> Self {
+        self.restore_threads = n;
+        self
+    }
+
+    /// limits the reads of the data & hint files that restore the keydir when the db
+    /// is opened to `bytes_per_sec`, shared by all the restore threads. unlimited by
+    /// default
+    pub fn with_restore_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.restore_rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// sets where the keydir keeps its entries. `KeyDirKind::Compact` takes far less
+    /// memory per key at some cost in speed, `KeyDirKind::Ordered` keeps the keys sorted
+    /// for `scan` & `scan_prefix` and `KeyDirKind::Disk` keeps them in an index in the
+    /// cask directory for key sets that don't fit in memory
+    pub fn with_keydir(mut self, kind: KeyDirKind) -> Self {
+        self.keydir = kind;
+        self
+    }
+
     pub fn with_cask<T: Into<String>>(mut self, cask: T) -> Self {
         self.cask = Some(cask.into());
         self
     }
 
+    /// builds a db without background threads. compaction thresholds are then only
+    /// checked by calling `HydraDB::compact_if_due`
     pub fn build(self) -> Result<HydraDB> {
+        if self.compaction.is_enabled() {
+            warn!(
+                "compaction thresholds are set but `build` starts no background compaction. \
+                 use `build_shared` or call `compact_if_due`"
+            );
+        }
+        self.open()
+    }
+
+    fn open(self) -> Result<HydraDB> {
         HydraDB::new(
             self.cask.unwrap(),
             self.max_file_size_threshold,
             self.cache_size,
+            self.durability,
+            self.compaction,
+            self.merge_rate_limit,
+            self.compaction_filter,
+            self.keydir_snapshot,
+            self.restore_threads,
+            self.restore_rate_limit,
+            self.keydir,
         )
     }
+
+    /// builds a shared db & starts its background threads: compaction if any
+    /// compaction threshold is set & keydir snapshots if they are on
+    pub fn build_shared(self) -> Result<Arc<HydraDB>> {
+        let db = Arc::new(self.open()?);
+        db.start_background_threads();
+        Ok(db)
+    }
 }
@@ -0,0 +1,404 @@
+use crate::key_dir::{KeyDirBackend, KeyDirEntry, KeyDirKind, KeyDirMemory};
+use anyhow::Result;
+use hashbrown::HashTable;
+use std::hash::{BuildHasher, RandomState};
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::RwLock;
+use std::thread;
+
+/// a keydir entry packed into 30 bytes: a u32 file id, the u32 value size, a 48-bit
+/// value position, the timestamp & the expiry
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PackedEntry(pub [u8; 30]);
+
+impl PackedEntry {
+    /// packs `entry`, or returns `None` if its file id or value position is too big
+    pub fn pack(entry: &KeyDirEntry) -> Option<Self> {
+        let file_id = u32::try_from(entry.file_id).ok()?;
+        if entry.val_pos >= 1 << 48 {
+            return None;
+        }
+
+        let mut packed = [0; 30];
+        packed[..4].copy_from_slice(&file_id.to_le_bytes());
+        packed[4..8].copy_from_slice(&entry.val_sz.to_le_bytes());
+        packed[8..14].copy_from_slice(&entry.val_pos.to_le_bytes()[..6]);
+        packed[14..22].copy_from_slice(&entry.tstamp.to_le_bytes());
+        packed[22..].copy_from_slice(&entry.expiry.to_le_bytes());
+        Some(Self(packed))
+    }
+
+    pub fn unpack(&self) -> KeyDirEntry {
+        let packed = &self.0;
+        let mut val_pos = [0; 8];
+        val_pos[..6].copy_from_slice(&packed[8..14]);
+
+        KeyDirEntry::new(
+            u32::from_le_bytes(packed[..4].try_into().unwrap()) as usize,
+            u32::from_le_bytes(packed[4..8].try_into().unwrap()),
+            u64::from_le_bytes(val_pos),
+            u64::from_le_bytes(packed[14..22].try_into().unwrap()),
+            u64::from_le_bytes(packed[22..].try_into().unwrap()),
+        )
+    }
+}
+
+/// where a key lives in the arena of its shard, along with its entry. 40 bytes: a
+/// 48-bit offset & a u32 length for the key and the packed entry
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    key_off: [u8; 6],
+    key_len: [u8; 4],
+    entry: PackedEntry,
+}
+
+impl Slot {
+    fn new(key: Range<usize>, entry: PackedEntry) -> Self {
+        let mut key_off = [0; 6];
+        key_off.copy_from_slice(&(key.start as u64).to_le_bytes()[..6]);
+        Self {
+            key_off,
+            key_len: (key.len() as u32).to_le_bytes(),
+            entry,
+        }
+    }
+
+    fn key(&self) -> Range<usize> {
+        let mut key_off = [0; 8];
+        key_off[..6].copy_from_slice(&self.key_off);
+        let start = u64::from_le_bytes(key_off) as usize;
+        start..start + u32::from_le_bytes(self.key_len) as usize
+    }
+}
+
+/// a slot for an entry that doesn't pack, one in a file past id `u32::MAX` or with a
+/// value past 2^48 bytes into its file. it keeps the entry as it is
+#[derive(Debug, Clone)]
+struct WideSlot {
+    key: Range<usize>,
+    entry: KeyDirEntry,
+}
+
+/// a part of the keydir. the keys are stored back to back in an arena instead of
+/// being allocated one by one
+#[derive(Debug, Default)]
+struct Shard {
+    table: HashTable<Slot>,
+    // the few entries that don't pack
+    wide: HashTable<WideSlot>,
+    arena: Vec<u8>,
+    // bytes of the arena taken by keys that were deleted
+    garbage: usize,
+}
+
+impl Shard {
+    /// calls `f` with the entry of `k`, if any, & stores whatever it leaves there
+    fn update(
+        &mut self,
+        hasher: &RandomState,
+        hash: u64,
+        k: &[u8],
+        f: &mut dyn FnMut(&mut Option<KeyDirEntry>),
+    ) {
+        let arena = &self.arena;
+        if let Ok(mut occupied) = self.table.find_entry(hash, |slot| &arena[slot.key()] == k) {
+            let mut cur = Some(occupied.get().entry.unpack());
+            f(&mut cur);
+            if let Some(packed) = cur.as_ref().and_then(PackedEntry::pack) {
+                occupied.get_mut().entry = packed;
+                return;
+            }
+            let (slot, _) = occupied.remove();
+            self.place(hasher, hash, slot.key(), cur);
+        } else if let Ok(occupied) = self
+            .wide
+            .find_entry(hash, |slot| &arena[slot.key.clone()] == k)
+        {
+            let mut cur = Some(occupied.get().entry.clone());
+            f(&mut cur);
+            let (slot, _) = occupied.remove();
+            self.place(hasher, hash, slot.key, cur);
+        } else {
+            let mut cur = None;
+            f(&mut cur);
+            if cur.is_some() {
+                let start = self.arena.len();
+                self.arena.extend_from_slice(k);
+                self.place(hasher, hash, start..self.arena.len(), cur);
+            }
+        }
+    }
+
+    /// stores `entry` for the key at `key` in the arena, packed if it fits. `None`
+    /// gives the key's space back
+    fn place(
+        &mut self,
+        hasher: &RandomState,
+        hash: u64,
+        key: Range<usize>,
+        entry: Option<KeyDirEntry>,
+    ) {
+        let arena = &self.arena;
+        match entry {
+            Some(entry) => match PackedEntry::pack(&entry) {
+                Some(packed) => {
+                    self.table
+                        .insert_unique(hash, Slot::new(key, packed), |slot| {
+                            hasher.hash_one(&arena[slot.key()])
+                        });
+                }
+                None => {
+                    self.wide
+                        .insert_unique(hash, WideSlot { key, entry }, |slot| {
+                            hasher.hash_one(&arena[slot.key.clone()])
+                        });
+                }
+            },
+            None => {
+                self.garbage += key.len();
+                self.compact_arena(hasher);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.table.len() + self.wide.len()
+    }
+
+    /// calls `f` with every key & its entry
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) {
+        for slot in self.table.iter() {
+            f(&self.arena[slot.key()], &slot.entry.unpack());
+        }
+        for slot in self.wide.iter() {
+            f(&self.arena[slot.key.clone()], &slot.entry);
+        }
+    }
+
+    /// copies the live keys to a new arena once deleted ones take up half of it
+    fn compact_arena(&mut self, hasher: &RandomState) {
+        if self.garbage * 2 < self.arena.len() {
+            return;
+        }
+
+        let mut arena = Vec::with_capacity(self.arena.len() - self.garbage);
+        let mut copy = |key: Range<usize>| {
+            let start = arena.len();
+            arena.extend_from_slice(&self.arena[key]);
+            start..arena.len()
+        };
+        for slot in self.table.iter_mut() {
+            *slot = Slot::new(copy(slot.key()), slot.entry);
+        }
+        for slot in self.wide.iter_mut() {
+            slot.key = copy(slot.key.clone());
+        }
+        self.arena = arena;
+        self.garbage = 0;
+
+        let arena = &self.arena;
+        self.table
+            .shrink_to_fit(|slot| hasher.hash_one(&arena[slot.key()]));
+        self.wide
+            .shrink_to_fit(|slot| hasher.hash_one(&arena[slot.key.clone()]));
+    }
+}
+
+/// a keydir that trades some speed for a much smaller footprint. entries are packed
+/// & the keys of a shard share one allocation
+#[derive(Debug)]
+pub(crate) struct CompactKeyDir {
+    hasher: RandomState,
+    shards: Box<[RwLock<Shard>]>,
+}
+
+impl Default for CompactKeyDir {
+    fn default() -> Self {
+        let shards = thread::available_parallelism().map_or(1, |n| n.get()) * 4;
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..shards.next_power_of_two())
+                .map(|_| RwLock::default())
+                .collect(),
+        }
+    }
+}
+
+impl CompactKeyDir {
+    fn shard(&self, k: &[u8]) -> (u64, &RwLock<Shard>) {
+        let hash = self.hasher.hash_one(k);
+        (hash, &self.shards[self.shard_of(hash)])
+    }
+
+    fn shard_of(&self, hash: u64) -> usize {
+        // the table picks slots by the low bits of the hash, so pick shards by the high
+        (hash >> 32) as usize & (self.shards.len() - 1)
+    }
+}
+
+impl KeyDirBackend for CompactKeyDir {
+    fn get(&self, k: &[u8]) -> Result<Option<KeyDirEntry>> {
+        let (hash, shard) = self.shard(k);
+        let shard = shard.read().unwrap();
+        let arena = &shard.arena;
+        Ok(
+            match shard.table.find(hash, |slot| &arena[slot.key()] == k) {
+                Some(slot) => Some(slot.entry.unpack()),
+                None => shard
+                    .wide
+                    .find(hash, |slot| &arena[slot.key.clone()] == k)
+                    .map(|slot| slot.entry.clone()),
+            },
+        )
+    }
+
+    fn update(&self, k: &[u8], f: &mut dyn FnMut(&mut Option<KeyDirEntry>)) -> Result<()> {
+        let (hash, shard) = self.shard(k);
+        shard.write().unwrap().update(&self.hasher, hash, k, f);
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        for part in 0..self.shards.len() {
+            self.for_each_in(part, f)?;
+        }
+        Ok(())
+    }
+
+    fn parts(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn part_of(&self, k: &[u8]) -> usize {
+        self.shard_of(self.hasher.hash_one(k))
+    }
+
+    fn for_each_in(&self, part: usize, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        self.shards[part].read().unwrap().for_each(f);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    fn clear(&self) -> Result<()> {
+        for shard in &self.shards {
+            *shard.write().unwrap() = Shard::default();
+        }
+        Ok(())
+    }
+
+    fn memory(&self) -> KeyDirMemory {
+        let (mut key_bytes, mut table_bytes) = (0, 0);
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            key_bytes += shard.arena.capacity() as u64;
+            // every bucket has a control byte besides its slot
+            table_bytes += (shard.table.capacity() * (size_of::<Slot>() + 1)
+                + shard.wide.capacity() * (size_of::<WideSlot>() + 1))
+                as u64;
+        }
+
+        KeyDirMemory {
+            kind: KeyDirKind::Compact,
+            keys: self.len(),
+            key_bytes,
+            table_bytes,
+            disk_bytes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::{CompactKeyDir, PackedEntry, Slot};
+    use crate::key_dir::{KeyDirBackend, KeyDirEntry};
+
+    #[test]
+    fn test_packed_entry() {
+        let entry = KeyDirEntry::new(u32::MAX as usize, 7, (1 << 48) - 1, u64::MAX, 42);
+        let unpacked = PackedEntry::pack(&entry).unwrap().unpack();
+        assert_eq!(
+            (unpacked.file_id, unpacked.val_sz, unpacked.val_pos),
+            (entry.file_id, entry.val_sz, entry.val_pos)
+        );
+        assert_eq!((unpacked.tstamp, unpacked.expiry), (u64::MAX, 42));
+        assert_eq!(size_of::<Slot>(), 40);
+
+        let entry = KeyDirEntry::new(u32::MAX as usize + 1, 7, 0, 0, 0);
+        assert!(PackedEntry::pack(&entry).is_none());
+        let entry = KeyDirEntry::new(1, 7, 1 << 48, 0, 0);
+        assert!(PackedEntry::pack(&entry).is_none());
+    }
+
+    #[test]
+    fn test_compact_key_dir_wide_entries() {
+        let store = CompactKeyDir::default();
+        let wide = KeyDirEntry::new(u32::MAX as usize + 1, 4, 1 << 48, 0, 0);
+        store
+            .put("abhi".into(), KeyDirEntry::new(1, 4, 7, 0, 0))
+            .unwrap();
+        store.put("pads".into(), wide.clone()).unwrap();
+        assert_eq!(store.get(b"pads").unwrap().unwrap().file_id, wide.file_id);
+        assert_eq!(store.get(b"pads").unwrap().unwrap().val_pos, wide.val_pos);
+
+        // an entry moves between the packed & the wide slots as it changes
+        store.put("abhi".into(), wide.clone()).unwrap();
+        assert_eq!(store.get(b"abhi").unwrap().unwrap().file_id, wide.file_id);
+        store
+            .put("pads".into(), KeyDirEntry::new(2, 4, 9, 0, 0))
+            .unwrap();
+        assert_eq!(store.get(b"pads").unwrap().unwrap().val_pos, 9);
+        assert_eq!(store.len(), 2);
+
+        let mut seen = 0;
+        store.for_each(&mut |_, _| seen += 1).unwrap();
+        assert_eq!(seen, 2);
+
+        assert!(store.del(b"abhi").unwrap().is_some());
+        assert!(store.get(b"abhi").unwrap().is_none());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_compact_key_dir() {
+        let store = CompactKeyDir::default();
+        for i in 0..10_000u64 {
+            store
+                .update(format!("key{i}").as_bytes(), &mut |cur| {
+                    *cur = Some(KeyDirEntry::new(1, 4, i, 0, 0))
+                })
+                .unwrap();
+        }
+        assert_eq!(store.len(), 10_000);
+        assert_eq!(store.get(b"key1234").unwrap().unwrap().val_pos, 1234);
+        let arena = store.memory().key_bytes;
+
+        // deleting most keys gives their arena space back
+        for i in 100..10_000 {
+            store
+                .update(format!("key{i}").as_bytes(), &mut |cur| *cur = None)
+                .unwrap();
+        }
+        assert_eq!(store.len(), 100);
+        assert!(store.get(b"key1234").unwrap().is_none());
+        assert_eq!(store.get(b"key42").unwrap().unwrap().val_pos, 42);
+        assert!(store.memory().key_bytes < arena / 4);
+
+        let mut seen = 0;
+        store
+            .for_each(&mut |k, entry| {
+                assert_eq!(k, format!("key{}", entry.val_pos).as_bytes());
+                seen += 1;
+            })
+            .unwrap();
+        assert_eq!(seen, 100);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::format::FormatVersion;
+use crate::key_dir::KeyDirEntry;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// how many bytes of a data file are taken up by records that are still live and by
+/// ones that were superseded or deleted
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileStats {
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl FileStats {
+    /// fraction of the file's records that are dead
+    pub fn dead_ratio(&self) -> f64 {
+        let total = self.live_bytes + self.dead_bytes;
+        if total == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / total as f64
+        }
+    }
+}
+
+/// size of a record with the given key & value sizes in a file of `version`
+pub(crate) fn record_sz(version: FormatVersion, ksz: usize, vsz: u32) -> u64 {
+    (version.header_sz() + ksz) as u64 + vsz as u64
+}
+
+/// live/dead byte counts of every data file in a cask
+#[derive(Debug, Default)]
+pub(crate) struct Fragmentation {
+    // file id -> (stats, format version of the file)
+    files: Mutex<BTreeMap<usize, (FileStats, FormatVersion)>>,
+}
+
+impl Fragmentation {
+    /// starts tracking the file `file_id` with the given stats, replacing any old ones
+    pub fn track(&self, file_id: usize, version: FormatVersion, stats: FileStats) {
+        self.files.lock().unwrap().insert(file_id, (stats, version));
+    }
+
+    /// stops tracking the file `file_id`
+    pub fn remove(&self, file_id: usize) {
+        self.files.lock().unwrap().remove(&file_id);
+    }
+
+    /// accounts for a record appended to the file `file_id`. files that aren't tracked
+    /// yet are new ones in the current format
+    pub fn add(&self, file_id: usize, bytes: u64, live: bool) {
+        let mut files = self.files.lock().unwrap();
+        let (stats, _) = files
+            .entry(file_id)
+            .or_insert((FileStats::default(), FormatVersion::CURRENT));
+        if live {
+            stats.live_bytes += bytes;
+        } else {
+            stats.dead_bytes += bytes;
+        }
+    }
+
+    /// marks the record of `entry` under a key of `ksz` bytes as dead
+    pub fn supersede(&self, ksz: usize, entry: &KeyDirEntry) {
+        let mut files = self.files.lock().unwrap();
+        if let Some((stats, version)) = files.get_mut(&entry.file_id) {
+            let bytes = record_sz(*version, ksz, entry.val_sz).min(stats.live_bytes);
+            stats.live_bytes -= bytes;
+            stats.dead_bytes += bytes;
+        }
+    }
+
+    /// the stats of every tracked file
+    pub fn stats(&self) -> BTreeMap<usize, FileStats> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&file_id, &(stats, _))| (file_id, stats))
+            .collect()
+    }
+}
+
+/// what a compaction filter wants done with a live record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// carry the record over as it is
+    Keep,
+    /// drop the record & delete its key
+    Remove,
+    /// carry the record over with this value instead
+    ChangeValue(Bytes),
+}
+
+/// application rules for obsolete data, applied to every live record a merge copies.
+/// deleted & expired records never reach the filter
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision;
+}
+
+impl<F> CompactionFilter for F
+where
+    F: Fn(&[u8], &[u8]) -> FilterDecision + Send + Sync,
+{
+    fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision {
+        self(key, value)
+    }
+}
+
+/// the compaction filter of a db, if any. keeps every record without one
+#[derive(Clone, Default)]
+pub struct CompactionFilterHandle(Option<Arc<dyn CompactionFilter>>);
+
+impl CompactionFilterHandle {
+    pub fn new(filter: impl CompactionFilter + 'static) -> Self {
+        Self(Some(Arc::new(filter)))
+    }
+
+    pub fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision {
+        match &self.0 {
+            Some(filter) => filter.filter(key, value),
+            None => FilterDecision::Keep,
+        }
+    }
+}
+
+impl Debug for CompactionFilterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CompactionFilterHandle")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+/// hours of the day (utc) during which background compaction may run.
+/// `start` is inclusive & `end` exclusive. a window may wrap around midnight, and one
+/// that starts & ends at the same hour spans the whole day
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl CompactionWindow {
+    /// checks if the window contains the time `now` (in millis since the unix epoch)
+    pub fn contains(&self, now: u64) -> bool {
+        let hour = (now / 3_600_000 % 24) as u8;
+        match self.start_hour.cmp(&self.end_hour) {
+            std::cmp::Ordering::Less => (self.start_hour..self.end_hour).contains(&hour),
+            std::cmp::Ordering::Greater => hour >= self.start_hour || hour < self.end_hour,
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+/// when the background compaction thread merges the immutable data files.
+/// a merge is due once any of the configured thresholds is crossed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    /// fraction of dead bytes across the immutable files
+    pub dead_ratio: Option<f64>,
+    /// total dead bytes across the immutable files
+    pub dead_bytes: Option<u64>,
+    /// number of immutable files
+    pub file_count: Option<usize>,
+    /// merges only up to this many of the most fragmented files instead of all of them
+    pub max_files: Option<usize>,
+    /// restricts compaction to some hours of the day
+    pub window: Option<CompactionWindow>,
+    /// how often the thresholds are checked
+    pub interval: Duration,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            dead_ratio: None,
+            dead_bytes: None,
+            file_count: None,
+            max_files: None,
+            window: None,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl CompactionPolicy {
+    /// whether any threshold is set, i.e. whether background compaction is on
+    pub fn is_enabled(&self) -> bool {
+        self.dead_ratio.is_some() || self.dead_bytes.is_some() || self.file_count.is_some()
+    }
+
+    /// checks the stats of the immutable files against the thresholds at time `now`
+    pub fn is_due<'a>(&self, files: impl IntoIterator<Item = &'a FileStats>, now: u64) -> bool {
+        if self.window.is_some_and(|window| !window.contains(now)) {
+            return false;
+        }
+
+        let (mut count, mut live, mut dead) = (0, 0, 0);
+        for stats in files {
+            count += 1;
+            live += stats.live_bytes;
+            dead += stats.dead_bytes;
+        }
+        let total = FileStats {
+            live_bytes: live,
+            dead_bytes: dead,
+        };
+
+        // a single file without dead records is as compact as it gets
+        (dead > 0
+            && (self.dead_ratio.is_some_and(|r| total.dead_ratio() >= r)
+                || self.dead_bytes.is_some_and(|b| dead >= b)))
+            || self.file_count.is_some_and(|n| count > 1 && count >= n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompactionPolicy, CompactionWindow, FileStats};
+
+    const HOUR: u64 = 3_600_000;
+
+    #[test]
+    fn test_compaction_window() {
+        let day = CompactionWindow {
+            start_hour: 9,
+            end_hour: 17,
+        };
+        assert!(day.contains(9 * HOUR));
+        assert!(!day.contains(17 * HOUR));
+        assert!(!day.contains(24 * HOUR + 3 * HOUR));
+
+        let night = CompactionWindow {
+            start_hour: 22,
+            end_hour: 4,
+        };
+        assert!(night.contains(23 * HOUR));
+        assert!(night.contains(24 * HOUR + 2 * HOUR));
+        assert!(!night.contains(12 * HOUR));
+    }
+
+    #[test]
+    fn test_compaction_policy() {
+        let stats = |live_bytes, dead_bytes| FileStats {
+            live_bytes,
+            dead_bytes,
+        };
+        let files = [stats(60, 40), stats(100, 0)];
+
+        assert!(!CompactionPolicy::default().is_due(&files, 0));
+
+        let policy = CompactionPolicy {
+            dead_ratio: Some(0.25),
+            ..Default::default()
+        };
+        assert!(!policy.is_due(&files, 0));
+        assert!(policy.is_due(&files[..1], 0));
+
+        let policy = CompactionPolicy {
+            dead_bytes: Some(40),
+            ..Default::default()
+        };
+        assert!(policy.is_due(&files, 0));
+        assert!(!policy.is_due(&files[1..], 0));
+
+        let policy = CompactionPolicy {
+            file_count: Some(2),
+            window: Some(CompactionWindow {
+                start_hour: 1,
+                end_hour: 2,
+            }),
+            ..Default::default()
+        };
+        assert!(policy.is_due(&files, HOUR));
+        assert!(!policy.is_due(&files, 0));
+        assert!(!policy.is_due(&files[..1], HOUR));
+    }
+}
@@ -1,12 +1,40 @@
+use crate::format::{
+    FormatVersion, MAX_HEADER_SZ, RecordHeader, RecordType, read_version, v0_record_type,
+};
+use crate::rate_limiter::RateLimiter;
 use anyhow::Result;
 use std::fs::File;
-use std::io::{BufReader, Read, Seek};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// fills `buf` completely from `reader`.
+/// returns `Ok(false)` on a clean eof, i.e. when not a single byte could be read,
+/// and an `UnexpectedEof` error if the reader ran dry midway (a torn header)
+pub(crate) fn read_header(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    match read {
+        0 => Ok(false),
+        n if n == buf.len() => Ok(true),
+        _ => Err(ErrorKind::UnexpectedEof.into()),
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Default)]
 pub struct DataFileEntry {
     pub crc: u32,
-    pub tstamp: u32,
+    pub rtype: RecordType,
+    pub tstamp: u64,
+    pub expiry: u64,
     pub ksz: u32,
     pub vsz: u32,
     pub key: Vec<u8>,
@@ -22,155 +50,207 @@ impl DataFileEntry {
             ..Default::default()
         }
     }
+
+    /// checks the stored crc against the one computed from the entry's contents
+    pub fn crc_matches(&self, version: FormatVersion) -> bool {
+        let header = RecordHeader {
+            crc: self.crc,
+            rtype: self.rtype,
+            tstamp: self.tstamp,
+            expiry: self.expiry,
+            ksz: self.ksz,
+            vsz: self.vsz,
+        };
+        header.calc_crc(version, &self.key, &self.val) == self.crc
+    }
+}
+
+/// opens the data file at `path` positioned at its first record.
+/// returns the file, its format version & its length
+fn open_data_file(path: impl AsRef<Path>) -> Result<(File, FormatVersion, u64)> {
+    let path: PathBuf = path.as_ref().to_path_buf();
+    let mut file = File::options().read(true).open(&path)?;
+    let len = file.metadata()?.len();
+    let version = read_version(&file)?;
+    file.seek(SeekFrom::Start(version.data_start()))?;
+
+    Ok((file, version, len))
 }
 
 /// iterates over a data file
 pub struct DataFileIterator {
-    buf: [u8; 16], // 4 crc + 4 tstamp + 4 ksz + 4 vsz
+    buf: [u8; MAX_HEADER_SZ],
     reader: BufReader<File>,
+    version: FormatVersion,
+    // current read offset & total length of the file, used to reject
+    // records that claim to extend past the end of the file
+    pos: u64,
+    len: u64,
 }
 
 impl DataFileIterator {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
-        let path: PathBuf = path.as_ref().to_path_buf();
-        let file = File::options().read(true).open(&path)?;
+        let (file, version, len) = open_data_file(path)?;
 
         Ok(Self {
-            buf: [0; 16],
+            buf: [0; MAX_HEADER_SZ],
             reader: BufReader::new(file),
+            version,
+            pos: version.data_start(),
+            len,
         })
     }
+
+    /// the format version of the file being iterated
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// offset of the first record in the file
+    pub fn data_start(&self) -> u64 {
+        self.version.data_start()
+    }
+
+    /// moves on to the record at `offset`
+    pub fn seek(&mut self, offset: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.pos = offset;
+        Ok(())
+    }
 }
 
 impl Iterator for DataFileIterator {
     type Item = Result<DataFileEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(size) = self.reader.read(&mut self.buf)
-            && size != 0
-        {
-            let mut i = 0;
-            let mut j = 3;
-
-            let crc = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
-
-            let tstamp = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
-
-            let ksz = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
-
-            let vsz = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-
-            // read key using ksz, val using vsz
-            let mut key = vec![0; ksz as usize];
-
-            if let Err(e) = self.reader.read_exact(&mut key) {
-                return Some(Err(e.into()));
-            }
-
-            let val_pos = self.reader.stream_position().unwrap();
-
-            let mut val = vec![0; vsz as usize];
-            if let Err(e) = self.reader.read_exact(&mut val) {
-                return Some(Err(e.into()));
-            }
-
-            let entry = DataFileEntry {
-                crc,
-                tstamp,
-                ksz,
-                vsz,
-                key,
-                val,
-                val_pos,
-            };
-
-            Some(Ok(entry))
-        } else {
-            None
-        }
+        let mut entry = DataFileEntry::default();
+        next_entry(
+            &mut self.reader,
+            &mut self.buf,
+            self.version,
+            &mut self.pos,
+            self.len,
+            &mut entry,
+        )
+        .map(|res| res.map(|_| entry))
     }
 }
 
 pub struct OptimizedDataFileIterator {
-    buf: [u8; 16], // 4 crc + 4 tstamp + 4 ksz + 4 vsz
+    buf: [u8; MAX_HEADER_SZ],
     reader: BufReader<File>,
+    version: FormatVersion,
+    // current read offset & total length of the file, used to reject
+    // records that claim to extend past the end of the file
+    pos: u64,
+    len: u64,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl OptimizedDataFileIterator {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
-        let path: PathBuf = path.as_ref().to_path_buf();
-        let file = File::options().read(true).open(&path)?;
+        let (file, version, len) = open_data_file(path)?;
 
         Ok(Self {
-            buf: [0; 16],
+            buf: [0; MAX_HEADER_SZ],
             reader: BufReader::new(file),
+            version,
+            pos: version.data_start(),
+            len,
+            limiter: None,
         })
     }
 
-    pub fn next_into(&mut self, entry: &mut DataFileEntry) -> Option<Result<()>> {
-        if let Ok(size) = self.reader.read(&mut self.buf)
-            && size != 0
-        {
-            let mut i = 0;
-            let mut j = 3;
-
-            let crc = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
+    /// throttles the reads to the rate of `limiter`
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
 
-            let tstamp = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
+    /// the format version of the file being iterated
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
 
-            let ksz = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
+    pub fn next_into(&mut self, entry: &mut DataFileEntry) -> Option<Result<()>> {
+        let start = self.pos;
+        let res = next_entry(
+            &mut self.reader,
+            &mut self.buf,
+            self.version,
+            &mut self.pos,
+            self.len,
+            entry,
+        );
 
-            let vsz = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(self.pos - start);
+        }
+        res
+    }
+}
 
-            entry.crc = crc;
-            entry.tstamp = tstamp;
-            entry.ksz = ksz;
-            entry.vsz = vsz;
+/// reads the next record from `reader` into `entry`, reusing its buffers
+fn next_entry(
+    reader: &mut BufReader<File>,
+    buf: &mut [u8; MAX_HEADER_SZ],
+    version: FormatVersion,
+    pos: &mut u64,
+    len: u64,
+    entry: &mut DataFileEntry,
+) -> Option<Result<()>> {
+    let buf = &mut buf[..version.header_sz()];
+    match read_header(reader, buf) {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => return Some(Err(e.into())),
+    }
 
-            // read key using ksz, val using vsz
-            // let mut key = vec![0; ksz as usize];
-            entry.key.resize(ksz as usize, 0);
+    let header = match RecordHeader::decode(version, buf) {
+        Ok(header) => header,
+        Err(e) => return Some(Err(e.into())),
+    };
+    let RecordHeader {
+        crc,
+        rtype,
+        tstamp,
+        expiry,
+        ksz,
+        vsz,
+    } = header;
+
+    *pos += buf.len() as u64;
+    if *pos + ksz as u64 + vsz as u64 > len {
+        return Some(Err(io::Error::from(ErrorKind::UnexpectedEof).into()));
+    }
 
-            if let Err(e) = self.reader.read_exact(&mut entry.key) {
-                return Some(Err(e.into()));
-            }
+    entry.crc = crc;
+    entry.rtype = rtype;
+    entry.tstamp = tstamp;
+    entry.expiry = expiry;
+    entry.ksz = ksz;
+    entry.vsz = vsz;
+
+    // read key using ksz, val using vsz
+    entry.key.resize(ksz as usize, 0);
+    if let Err(e) = reader.read_exact(&mut entry.key) {
+        return Some(Err(e.into()));
+    }
 
-            let val_pos = self.reader.stream_position().unwrap();
-            entry.val_pos = val_pos;
+    let val_pos = *pos + ksz as u64;
+    entry.val_pos = val_pos;
+    *pos = val_pos + vsz as u64;
 
-            // let mut val = vec![0; vsz as usize];
-            entry.val.resize(vsz as usize, 0);
-            if let Err(e) = self.reader.read_exact(&mut entry.val) {
-                return Some(Err(e.into()));
-            }
+    entry.val.resize(vsz as usize, 0);
+    if let Err(e) = reader.read_exact(&mut entry.val) {
+        return Some(Err(e.into()));
+    }
 
-            // let entry = DataFileEntry {
-            //     crc,
-            //     tstamp,
-            //     ksz,
-            //     vsz,
-            //     key,
-            //     val,
-            //     val_pos,
-            // };
-
-            Some(Ok(()))
-        } else {
-            None
-        }
+    if version == FormatVersion::V0 {
+        entry.rtype = v0_record_type(&entry.val);
     }
+
+    Some(Ok(()))
 }
 
 #[cfg(test)]
@@ -179,12 +259,14 @@ mod test {
     use std::io::Write;
 
     use crate::data_file_iter::{DataFileEntry, DataFileIterator, OptimizedDataFileIterator};
+    use crate::format::{FormatVersion, RecordType, encode_record, file_header};
 
     #[test]
     fn test_data_iter() {
         let mut file = File::options()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("data_file_iter_test")
             .unwrap();
 
@@ -204,7 +286,9 @@ mod test {
             entry,
             DataFileEntry {
                 crc: 0,
+                rtype: RecordType::Put,
                 tstamp: 1,
+                expiry: 0,
                 ksz: 4,
                 vsz: 4,
                 key: b"abhi".to_vec(),
@@ -221,6 +305,7 @@ mod test {
         let mut file = File::options()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("opt_data_file_iter_test")
             .unwrap();
 
@@ -241,7 +326,9 @@ mod test {
             entry,
             DataFileEntry {
                 crc: 0,
+                rtype: RecordType::Put,
                 tstamp: 1,
+                expiry: 0,
                 ksz: 4,
                 vsz: 4,
                 key: b"abhi".to_vec(),
@@ -252,4 +339,29 @@ mod test {
 
         let _ = fs::remove_file("opt_data_file_iter_test");
     }
+
+    #[test]
+    fn test_typed_data_iter() {
+        let mut data = file_header().to_vec();
+        encode_record(RecordType::Put, 1, 0, b"abhi", b"rust", &mut data);
+        encode_record(RecordType::Delete, 2, 0, b"abhi", b"", &mut data);
+        fs::write("typed_data_file_iter_test", &data).unwrap();
+
+        let mut iter = DataFileIterator::new("typed_data_file_iter_test").unwrap();
+        assert_eq!(iter.version(), FormatVersion::V3);
+
+        let entry = iter.next().unwrap().unwrap();
+        assert_eq!(entry.rtype, RecordType::Put);
+        assert_eq!(entry.val, b"rust");
+        assert_eq!(entry.val_pos, 5 + 29 + 4);
+        assert!(entry.crc_matches(FormatVersion::V3));
+
+        let entry = iter.next().unwrap().unwrap();
+        assert_eq!(entry.rtype, RecordType::Delete);
+        assert_eq!(entry.key, b"abhi");
+        assert!(entry.crc_matches(FormatVersion::V3));
+        assert!(iter.next().is_none());
+
+        let _ = fs::remove_file("typed_data_file_iter_test");
+    }
 }
@@ -0,0 +1,811 @@
+use crate::error::HydraError;
+use crate::key_dir::{KeyDirBackend, KeyDirEntry, KeyDirKind, KeyDirMemory};
+use crate::merge_journal::remove_if_exists;
+use crate::utils::sync_dir;
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use log::warn;
+use siphasher::sip::SipHasher24;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// name of the slot array in the directory of the index
+const SLOTS: &str = "keydir.slots";
+
+/// name of the log of keys & entries in the directory of the index
+const LOG: &str = "keydir.log";
+
+/// name of the file that describes an index that was closed cleanly. it's removed as
+/// soon as the index is opened, so an index without it may be half written
+const META: &str = "keydir.meta";
+
+/// the log starts with it so that no record sits at offset 0, which marks a free slot
+const LOG_MAGIC: &[u8; 4] = b"HYKI";
+
+const META_MAGIC: &[u8; 4] = b"HYKM";
+const META_VERSION: u8 = 2;
+/// magic, version, hash, the two seed halves, the slot count, the log length, the live,
+/// deleted & garbage counts and the crc of all of it
+const META_SZ: usize = 4 + 1 + 1 + 8 * 7 + 4;
+
+/// the hash the slots were placed by. an index placed by another one is rebuilt
+const HASH_SIPHASH_2_4: u8 = 1;
+
+/// a slot is the full hash of its key & the offset of its record in the log
+const SLOT_SZ: u64 = 16;
+const FREE: u64 = 0;
+const DELETED: u64 = u64::MAX;
+const MIN_SLOTS: u64 = 1024;
+
+/// the keys are walked in parts by the top bits of their hash, each with a pass over the log
+const PART_BITS: u32 = 4;
+
+/// a record is a live flag, the key size, the key & the entry
+const REC_HEADER_SZ: u64 = 1 + 4;
+
+/// file id + value size + value position + tstamp + expiry. entries are stored at full
+/// width as there is no memory to save on disk
+const ENTRY_SZ: u64 = 8 + 4 + 8 + 8 + 8;
+
+fn encode_entry(entry: &KeyDirEntry) -> [u8; ENTRY_SZ as usize] {
+    let mut buf = [0; ENTRY_SZ as usize];
+    buf[..8].copy_from_slice(&(entry.file_id as u64).to_le_bytes());
+    buf[8..12].copy_from_slice(&entry.val_sz.to_le_bytes());
+    buf[12..20].copy_from_slice(&entry.val_pos.to_le_bytes());
+    buf[20..28].copy_from_slice(&entry.tstamp.to_le_bytes());
+    buf[28..].copy_from_slice(&entry.expiry.to_le_bytes());
+    buf
+}
+
+fn decode_entry(buf: &[u8]) -> KeyDirEntry {
+    let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+    KeyDirEntry::new(
+        u64_at(0) as usize,
+        u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+        u64_at(12),
+        u64_at(20),
+        u64_at(28),
+    )
+}
+
+/// wraps an i/o failure on the index in the error callers match on
+fn index_err(e: io::Error) -> anyhow::Error {
+    anyhow::Error::new(e).context(HydraError::KeyDirIo)
+}
+
+/// the seed keys are hashed with. it's kept with the index so that the hashes of the
+/// keys stay the same when it's reopened
+#[derive(Debug, Clone, Copy)]
+struct Seed(u64, u64);
+
+impl Seed {
+    fn random() -> Self {
+        let state = RandomState::new();
+        Self(state.hash_one(0), state.hash_one(1))
+    }
+
+    // siphash-2-4 from a pinned crate always hashes the same for a seed, unlike the
+    // hashers of std, which may change or go away between releases
+    fn hash(&self, k: &[u8]) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.0, self.1);
+        hasher.write(k);
+        hasher.finish()
+    }
+
+    fn part_of(&self, k: &[u8]) -> usize {
+        (self.hash(k) >> (u64::BITS - PART_BITS)) as usize
+    }
+}
+
+/// where a key is in the index, or where it would go
+struct Probe {
+    slot: u64,
+    // offset of the record of the key & its entry
+    found: Option<(u64, KeyDirEntry)>,
+    // whether `slot` held a deleted key
+    reuses_deleted: bool,
+}
+
+/// a hash table on disk with open addressing. the slots point at records in a log,
+/// which is compacted once deleted records take up half of it
+#[derive(Debug)]
+struct Index {
+    seed: Seed,
+    dir: PathBuf,
+    slots: File,
+    num_slots: u64,
+    log: File,
+    log_len: u64,
+    live: usize,
+    // slots of deleted keys, which probes have to step over
+    deleted: u64,
+    // bytes of the log taken by deleted records
+    garbage: u64,
+    // set once a write fails part way, so that the index isn't reused
+    broken: bool,
+}
+
+fn create(path: impl AsRef<Path>, len: u64) -> io::Result<File> {
+    let file = File::options()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(len)?;
+    Ok(file)
+}
+
+fn open(path: impl AsRef<Path>) -> io::Result<File> {
+    File::options().read(true).write(true).open(path)
+}
+
+impl Index {
+    fn create(dir: &Path, seed: Seed) -> io::Result<Self> {
+        let log = create(dir.join(LOG), 0)?;
+        log.write_all_at(LOG_MAGIC, 0)?;
+
+        Ok(Self {
+            seed,
+            dir: dir.to_path_buf(),
+            slots: create(dir.join(SLOTS), MIN_SLOTS * SLOT_SZ)?,
+            num_slots: MIN_SLOTS,
+            log,
+            log_len: LOG_MAGIC.len() as u64,
+            live: 0,
+            deleted: 0,
+            garbage: 0,
+            broken: false,
+        })
+    }
+
+    /// opens the index that was closed in `dir`, if there is one. its description is
+    /// removed before it's handed out, as it stops matching the files with the first
+    /// write
+    fn reopen(dir: &Path) -> Result<Option<Self>> {
+        let meta = match fs::read(dir.join(META)) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        remove_if_exists(dir.join(META))?;
+        sync_dir(dir)?;
+
+        if meta.len() != META_SZ || &meta[..4] != META_MAGIC {
+            bail!("not a keydir index description");
+        }
+        if meta[4] != META_VERSION {
+            bail!("unknown keydir index version {}", meta[4]);
+        }
+        if meta[5] != HASH_SIPHASH_2_4 {
+            bail!("keydir index placed by unknown hash {}", meta[5]);
+        }
+        let (body, crc) = meta.split_at(META_SZ - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            bail!("keydir index description failed its crc");
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(body[6 + i * 8..14 + i * 8].try_into().unwrap());
+        let index = Self {
+            seed: Seed(u64_at(0), u64_at(1)),
+            dir: dir.to_path_buf(),
+            slots: open(dir.join(SLOTS))?,
+            num_slots: u64_at(2),
+            log: open(dir.join(LOG))?,
+            log_len: u64_at(3),
+            live: u64_at(4) as usize,
+            deleted: u64_at(5),
+            garbage: u64_at(6),
+            broken: false,
+        };
+        if index.slots.metadata()?.len() != index.num_slots * SLOT_SZ
+            || index.log.metadata()?.len() != index.log_len
+        {
+            bail!("keydir index files don't match their description");
+        }
+        Ok(Some(index))
+    }
+
+    /// makes the index durable & describes it for the next open to pick it up
+    fn close(&self) -> Result<()> {
+        self.slots.sync_all()?;
+        self.log.sync_all()?;
+
+        let mut meta = Vec::with_capacity(META_SZ);
+        meta.extend_from_slice(META_MAGIC);
+        meta.push(META_VERSION);
+        meta.push(HASH_SIPHASH_2_4);
+        for n in [
+            self.seed.0,
+            self.seed.1,
+            self.num_slots,
+            self.log_len,
+            self.live as u64,
+            self.deleted,
+            self.garbage,
+        ] {
+            meta.extend_from_slice(&n.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&meta);
+        meta.extend_from_slice(&crc.to_le_bytes());
+
+        let temp = self.dir.join(format!("{META}.tmp"));
+        let mut file = File::create(&temp)?;
+        file.write_all(&meta)?;
+        file.sync_all()?;
+        fs::rename(temp, self.dir.join(META))?;
+        sync_dir(&self.dir)
+    }
+
+    fn read_slot(&self, slot: u64) -> io::Result<(u64, u64)> {
+        let mut buf = [0; SLOT_SZ as usize];
+        self.slots.read_exact_at(&mut buf, slot * SLOT_SZ)?;
+        let (hash, off) = buf.split_at(8);
+        Ok((
+            u64::from_le_bytes(hash.try_into().unwrap()),
+            u64::from_le_bytes(off.try_into().unwrap()),
+        ))
+    }
+
+    fn write_slot(file: &File, slot: u64, hash: u64, off: u64) -> io::Result<()> {
+        let mut buf = [0; SLOT_SZ as usize];
+        buf[..8].copy_from_slice(&hash.to_le_bytes());
+        buf[8..].copy_from_slice(&off.to_le_bytes());
+        file.write_all_at(&buf, slot * SLOT_SZ)
+    }
+
+    /// reads the key & the entry of the record at `off`
+    fn read_record(&self, off: u64) -> io::Result<(Vec<u8>, KeyDirEntry)> {
+        let mut header = [0; REC_HEADER_SZ as usize];
+        self.log.read_exact_at(&mut header, off)?;
+        let ksz = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+
+        let mut rec = vec![0; ksz + ENTRY_SZ as usize];
+        self.log.read_exact_at(&mut rec, off + REC_HEADER_SZ)?;
+        let entry = decode_entry(&rec[ksz..]);
+        rec.truncate(ksz);
+        Ok((rec, entry))
+    }
+
+    fn find(&self, hash: u64, k: &[u8]) -> io::Result<Probe> {
+        let mut slot = hash % self.num_slots;
+        let mut deleted = None;
+        loop {
+            match self.read_slot(slot)? {
+                (_, FREE) => {
+                    return Ok(Probe {
+                        slot: deleted.unwrap_or(slot),
+                        found: None,
+                        reuses_deleted: deleted.is_some(),
+                    });
+                }
+                (_, DELETED) => {
+                    deleted.get_or_insert(slot);
+                }
+                (slot_hash, off) if slot_hash == hash => {
+                    let (key, entry) = self.read_record(off)?;
+                    if key == k {
+                        return Ok(Probe {
+                            slot,
+                            found: Some((off, entry)),
+                            reuses_deleted: false,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            slot = (slot + 1) % self.num_slots;
+        }
+    }
+
+    fn get(&self, k: &[u8]) -> io::Result<Option<KeyDirEntry>> {
+        let probe = self.find(self.seed.hash(k), k)?;
+        Ok(probe.found.map(|(_, entry)| entry))
+    }
+
+    /// calls `f` with the entry of `k` & stores what it leaves there. returns that
+    fn update(
+        &mut self,
+        k: &[u8],
+        f: &mut dyn FnMut(&mut Option<KeyDirEntry>),
+    ) -> io::Result<Option<KeyDirEntry>> {
+        let hash = self.seed.hash(k);
+        let probe = self.find(hash, k)?;
+        let mut cur = probe.found.as_ref().map(|(_, entry)| entry.clone());
+        f(&mut cur);
+
+        match (probe.found, &cur) {
+            (Some((off, _)), Some(new)) => {
+                let entry_off = off + REC_HEADER_SZ + k.len() as u64;
+                self.log.write_all_at(&encode_entry(new), entry_off)?;
+            }
+            (Some((off, _)), None) => {
+                self.log.write_all_at(&[0], off)?;
+                Self::write_slot(&self.slots, probe.slot, 0, DELETED)?;
+                self.live -= 1;
+                self.deleted += 1;
+                self.garbage += REC_HEADER_SZ + k.len() as u64 + ENTRY_SZ;
+                if self.garbage * 2 > self.log_len {
+                    self.compact_log()?;
+                }
+            }
+            (None, Some(new)) => {
+                let mut rec = Vec::with_capacity((REC_HEADER_SZ + ENTRY_SZ) as usize + k.len());
+                rec.push(1);
+                rec.extend_from_slice(&(k.len() as u32).to_le_bytes());
+                rec.extend_from_slice(k);
+                rec.extend_from_slice(&encode_entry(new));
+                self.log.write_all_at(&rec, self.log_len)?;
+
+                Self::write_slot(&self.slots, probe.slot, hash, self.log_len)?;
+                self.log_len += rec.len() as u64;
+                self.live += 1;
+                if probe.reuses_deleted {
+                    self.deleted -= 1;
+                }
+                // probes stay short while at most half of the slots are taken
+                if (self.live as u64 + self.deleted) * 2 > self.num_slots {
+                    self.resize()?;
+                }
+            }
+            (None, None) => {}
+        }
+        Ok(cur)
+    }
+
+    /// moves the keys to a slot array that is a quarter full, dropping deleted ones
+    fn resize(&mut self) -> io::Result<()> {
+        let num_slots = (self.live as u64 * 4).next_power_of_two().max(MIN_SLOTS);
+        let temp = self.dir.join(format!("{SLOTS}.tmp"));
+        let slots = create(&temp, num_slots * SLOT_SZ)?;
+
+        let mut reader = BufReader::new(&self.slots);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut buf = [0; SLOT_SZ as usize];
+        for _ in 0..self.num_slots {
+            reader.read_exact(&mut buf)?;
+            let hash = u64::from_le_bytes(buf[..8].try_into().unwrap());
+            let off = u64::from_le_bytes(buf[8..].try_into().unwrap());
+            if off == FREE || off == DELETED {
+                continue;
+            }
+
+            let mut slot = hash % num_slots;
+            loop {
+                let mut taken = [0; 8];
+                slots.read_exact_at(&mut taken, slot * SLOT_SZ + 8)?;
+                if u64::from_le_bytes(taken) == FREE {
+                    break;
+                }
+                slot = (slot + 1) % num_slots;
+            }
+            Self::write_slot(&slots, slot, hash, off)?;
+        }
+
+        fs::rename(temp, self.dir.join(SLOTS))?;
+        self.slots = slots;
+        self.num_slots = num_slots;
+        self.deleted = 0;
+        Ok(())
+    }
+
+    /// copies the live records to a new log & repoints their slots at them
+    fn compact_log(&mut self) -> io::Result<()> {
+        let temp = self.dir.join(format!("{LOG}.tmp"));
+        let mut log = BufWriter::new(create(&temp, 0)?);
+        log.write_all(LOG_MAGIC)?;
+        let mut log_len = LOG_MAGIC.len() as u64;
+
+        for slot in 0..self.num_slots {
+            let (hash, off) = self.read_slot(slot)?;
+            if off == FREE || off == DELETED {
+                continue;
+            }
+
+            let (key, entry) = self.read_record(off)?;
+            log.write_all(&[1])?;
+            log.write_all(&(key.len() as u32).to_le_bytes())?;
+            log.write_all(&key)?;
+            log.write_all(&encode_entry(&entry))?;
+            Self::write_slot(&self.slots, slot, hash, log_len)?;
+            log_len += REC_HEADER_SZ + key.len() as u64 + ENTRY_SZ;
+        }
+
+        let log = log.into_inner().map_err(|e| e.into_error())?;
+        fs::rename(temp, self.dir.join(LOG))?;
+        self.log = log;
+        self.log_len = log_len;
+        self.garbage = 0;
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> io::Result<()> {
+        let mut reader = BufReader::new(&self.log);
+        reader.seek(SeekFrom::Start(LOG_MAGIC.len() as u64))?;
+        let mut header = [0; REC_HEADER_SZ as usize];
+        let mut rec = vec![];
+
+        let mut off = LOG_MAGIC.len() as u64;
+        while off < self.log_len {
+            reader.read_exact(&mut header)?;
+            let ksz = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+            rec.resize(ksz + ENTRY_SZ as usize, 0);
+            reader.read_exact(&mut rec)?;
+            off += REC_HEADER_SZ + rec.len() as u64;
+
+            if header[0] == 1 {
+                f(&rec[..ksz], &decode_entry(&rec[ksz..]));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the most recently used entries of a disk keydir
+#[derive(Debug, Default)]
+struct EntryCache {
+    capacity: usize,
+    // key -> (entry, tick of its last use)
+    entries: HashMap<Bytes, (KeyDirEntry, u64)>,
+    // tick of last use -> key, oldest first
+    order: BTreeMap<u64, Bytes>,
+    tick: u64,
+}
+
+impl EntryCache {
+    fn get(&mut self, k: &[u8]) -> Option<KeyDirEntry> {
+        let (entry, last_used) = self.entries.get_mut(k)?;
+        let key = self.order.remove(last_used).unwrap();
+        self.tick += 1;
+        *last_used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, k: &[u8], entry: KeyDirEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(k);
+        self.tick += 1;
+        let key = Bytes::copy_from_slice(k);
+        self.entries.insert(key.clone(), (entry, self.tick));
+        self.order.insert(self.tick, key);
+
+        while self.entries.len() > self.capacity {
+            let (_, evicted) = self.order.pop_first().unwrap();
+            self.entries.remove(&evicted);
+        }
+    }
+
+    fn remove(&mut self, k: &[u8]) {
+        if let Some((_, last_used)) = self.entries.remove(k) {
+            self.order.remove(&last_used);
+        }
+    }
+}
+
+/// a keydir that lives in an on-disk hash index, for key sets that don't fit in
+/// memory. only the most recently used entries are kept in memory.
+///
+/// the index is kept across opens: it's described when the keydir is closed & the
+/// next open picks it up, so only what was written since needs to be replayed. an
+/// index that wasn't closed, as after a crash, is rebuilt from the data files.
+/// gets share the index, only writes take it for themselves
+#[derive(Debug)]
+pub(crate) struct DiskKeyDir {
+    index: RwLock<Index>,
+    // only ever locked while holding the index lock or on its own, never the other
+    // way around
+    cache: Mutex<EntryCache>,
+    // kept out of the index so that finding the part of a key doesn't lock it
+    seed: Seed,
+    restored: bool,
+}
+
+impl DiskKeyDir {
+    /// opens the index in the directory `dir` if it was closed there, or creates an
+    /// empty one, replacing whatever is left of an old one
+    pub fn open(dir: impl AsRef<Path>, cache_size: usize) -> Result<Self> {
+        let dir = dir.as_ref();
+        let reopened = match Index::reopen(dir) {
+            Ok(reopened) => reopened,
+            Err(e) => {
+                warn!("{}: rebuilding the keydir index: {e}", dir.display());
+                remove_if_exists(dir.join(META))?;
+                None
+            }
+        };
+        let restored = reopened.is_some();
+        let index = match reopened {
+            Some(index) => index,
+            None => Index::create(dir, Seed::random()).map_err(index_err)?,
+        };
+
+        Ok(Self {
+            seed: index.seed,
+            index: RwLock::new(index),
+            cache: Mutex::new(EntryCache {
+                capacity: cache_size,
+                ..Default::default()
+            }),
+            restored,
+        })
+    }
+}
+
+impl KeyDirBackend for DiskKeyDir {
+    fn get(&self, k: &[u8]) -> Result<Option<KeyDirEntry>> {
+        if let Some(entry) = self.cache.lock().unwrap().get(k) {
+            return Ok(Some(entry));
+        }
+
+        // the entry goes into the cache under the index lock so that a concurrent
+        // update can't be overwritten by what it replaced
+        let index = self.index.read().unwrap();
+        let entry = index.get(k).map_err(index_err)?;
+        if let Some(entry) = &entry {
+            self.cache.lock().unwrap().insert(k, entry.clone());
+        }
+        Ok(entry)
+    }
+
+    fn update(&self, k: &[u8], f: &mut dyn FnMut(&mut Option<KeyDirEntry>)) -> Result<()> {
+        let mut index = self.index.write().unwrap();
+        let updated = index.update(k, f);
+
+        let mut cache = self.cache.lock().unwrap();
+        match updated {
+            Ok(Some(entry)) => cache.insert(k, entry),
+            Ok(None) => cache.remove(k),
+            Err(e) => {
+                // there is no telling what a failed write left on disk
+                cache.remove(k);
+                index.broken = true;
+                return Err(index_err(e));
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        let index = self.index.read().unwrap();
+        index.for_each(f).map_err(index_err)
+    }
+
+    fn parts(&self) -> usize {
+        1 << PART_BITS
+    }
+
+    // every part is read from the whole index, so a walk takes a part at a time rather
+    // than reading the index again for every page
+    fn page_size(&self) -> usize {
+        usize::MAX
+    }
+
+    fn part_of(&self, k: &[u8]) -> usize {
+        self.seed.part_of(k)
+    }
+
+    fn for_each_in(&self, part: usize, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        let index = self.index.read().unwrap();
+        index
+            .for_each(&mut |k, entry| {
+                if self.seed.part_of(k) == part {
+                    f(k, entry)
+                }
+            })
+            .map_err(index_err)
+    }
+
+    fn len(&self) -> usize {
+        self.index.read().unwrap().live
+    }
+
+    fn clear(&self) -> Result<()> {
+        let mut index = self.index.write().unwrap();
+        let mut cache = self.cache.lock().unwrap();
+        *cache = EntryCache {
+            capacity: cache.capacity,
+            ..Default::default()
+        };
+        // keys stay in their parts, so the seed stays too. a failed clear may leave
+        // the old index half gone
+        index.broken = true;
+        *index = Index::create(&index.dir, self.seed).map_err(index_err)?;
+        Ok(())
+    }
+
+    fn memory(&self) -> KeyDirMemory {
+        let index = self.index.read().unwrap();
+        let cache = self.cache.lock().unwrap();
+        let key_bytes = cache.entries.keys().map(|k| k.len() as u64).sum();
+        // a cached entry sits in the map & in the lru order
+        let cached = size_of::<(Bytes, (KeyDirEntry, u64))>() + size_of::<(u64, Bytes)>();
+
+        KeyDirMemory {
+            kind: KeyDirKind::Disk {
+                cache_size: cache.capacity,
+            },
+            keys: index.live,
+            key_bytes,
+            table_bytes: (cache.entries.capacity() * cached) as u64,
+            disk_bytes: index.log_len + index.num_slots * SLOT_SZ,
+        }
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+
+    fn is_restored(&self) -> bool {
+        self.restored
+    }
+}
+
+impl Drop for DiskKeyDir {
+    fn drop(&mut self) {
+        // an index that a write panicked or failed in the middle of gets rebuilt
+        let Ok(index) = self.index.get_mut() else {
+            return;
+        };
+        if !index.broken
+            && let Err(e) = index.close()
+        {
+            warn!(
+                "{}: failed to close the keydir index: {e}",
+                index.dir.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::mem;
+
+    use super::{DiskKeyDir, HASH_SIPHASH_2_4, META, META_SZ, SLOTS};
+    use crate::error::HydraError;
+    use crate::key_dir::{KeyDirBackend, KeyDirEntry, KeyDirKind};
+
+    #[test]
+    fn test_disk_key_dir() {
+        let dir = "disk_key_dir_test";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let store = DiskKeyDir::open(dir, 16).unwrap();
+        assert!(!store.is_restored());
+        // enough keys to outgrow the initial slots a few times
+        for i in 0..5000 {
+            store
+                .put(format!("key{i}").into(), KeyDirEntry::new(1, 4, i, 0, 0))
+                .unwrap();
+        }
+        for i in 0..5000 {
+            let old = store
+                .put(format!("key{i}").into(), KeyDirEntry::new(2, 4, i, 0, 0))
+                .unwrap();
+            assert_eq!(old.unwrap().file_id, 1);
+        }
+        assert_eq!(store.len(), 5000);
+        assert_eq!(store.get(b"key4321").unwrap().unwrap().file_id, 2);
+
+        // entries are stored at full width
+        let wide = KeyDirEntry::new(u32::MAX as usize + 1, 4, 1 << 48, 0, 0);
+        store.put("key4999".into(), wide.clone()).unwrap();
+        let found = store.get(b"key4999").unwrap().unwrap();
+        assert_eq!((found.file_id, found.val_pos), (wide.file_id, wide.val_pos));
+        store
+            .put("key4999".into(), KeyDirEntry::new(2, 4, 4999, 0, 0))
+            .unwrap();
+        assert!(store.get(b"key5000").unwrap().is_none());
+
+        let memory = store.memory();
+        assert_eq!(memory.kind, KeyDirKind::Disk { cache_size: 16 });
+        assert!(memory.key_bytes <= 16 * 7);
+
+        // deleting most keys shrinks the log
+        for i in 100..5000 {
+            assert!(store.del(format!("key{i}").as_bytes()).unwrap().is_some());
+        }
+        assert!(store.del(b"key100").unwrap().is_none());
+        assert_eq!(store.len(), 100);
+        assert!(store.get(b"key4321").unwrap().is_none());
+        assert_eq!(store.get(b"key42").unwrap().unwrap().val_pos, 42);
+        assert!(store.memory().disk_bytes < memory.disk_bytes);
+
+        // the index is picked up again once it's closed
+        drop(store);
+        let store = DiskKeyDir::open(dir, 16).unwrap();
+        assert!(store.is_restored());
+        assert_eq!(store.len(), 100);
+        assert_eq!(store.get(b"key42").unwrap().unwrap().val_pos, 42);
+        assert!(store.get(b"key4321").unwrap().is_none());
+
+        let mut seen = 0;
+        store
+            .for_each(&mut |k, entry| {
+                assert_eq!(k, format!("key{}", entry.val_pos).as_bytes());
+                seen += 1;
+            })
+            .unwrap();
+        assert_eq!(seen, 100);
+
+        // but not if it wasn't closed, as after a crash
+        store
+            .put("key0".into(), KeyDirEntry::new(3, 4, 0, 0, 0))
+            .unwrap();
+        mem::forget(store);
+        let store = DiskKeyDir::open(dir, 16).unwrap();
+        assert!(!store.is_restored());
+        assert_eq!(store.len(), 0);
+
+        // nor if its slots were placed by another hash
+        store
+            .put("key42".into(), KeyDirEntry::new(1, 4, 42, 0, 0))
+            .unwrap();
+        drop(store);
+        let mut meta = fs::read(format!("{dir}/{META}")).unwrap();
+        meta[5] = HASH_SIPHASH_2_4 + 1;
+        let crc = crc32fast::hash(&meta[..META_SZ - 4]);
+        meta[META_SZ - 4..].copy_from_slice(&crc.to_le_bytes());
+        fs::write(format!("{dir}/{META}"), meta).unwrap();
+        let store = DiskKeyDir::open(dir, 16).unwrap();
+        assert!(!store.is_restored());
+        assert_eq!(store.len(), 0);
+
+        store
+            .put("key42".into(), KeyDirEntry::new(1, 4, 42, 0, 0))
+            .unwrap();
+        store.clear().unwrap();
+        assert_eq!(store.len(), 0);
+        assert!(store.get(b"key42").unwrap().is_none());
+
+        drop(store);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_disk_key_dir_io_error() {
+        let dir = "disk_key_dir_io_error_test";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let store = DiskKeyDir::open(dir, 0).unwrap();
+        store
+            .put("abhi".into(), KeyDirEntry::new(1, 4, 7, 0, 0))
+            .unwrap();
+
+        // the slots are cut off under the index
+        File::options()
+            .write(true)
+            .open(format!("{dir}/{SLOTS}"))
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        let err = store.get(b"abhi").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HydraError>(),
+            Some(&HydraError::KeyDirIo)
+        );
+        let err = store.put("pads".into(), KeyDirEntry::new(1, 4, 9, 0, 0));
+        assert!(err.is_err());
+
+        // an index that failed a write isn't picked up again
+        drop(store);
+        let store = DiskKeyDir::open(dir, 0).unwrap();
+        assert!(!store.is_restored());
+
+        drop(store);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,116 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// controls when appended records are forced from the os page cache to disk
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// fdatasync the active file before every write returns
+    Always,
+    /// concurrent writers wait for a shared fdatasync that covers all of their records
+    GroupCommit,
+    /// a background thread syncs the active file every given number of milliseconds.
+    /// a crash may lose writes made since the last sync
+    Interval(u64),
+    /// records are only handed to the os. a machine crash may lose recent writes
+    #[default]
+    None,
+}
+
+#[derive(Debug, Default)]
+struct GroupCommitState {
+    /// highest record sequence number known to be on disk
+    synced: u64,
+    /// whether some writer is currently running a sync on behalf of the others
+    syncing: bool,
+}
+
+/// lets concurrent writers share a single fdatasync.
+///
+/// a writer that needs its record to be durable either finds that a sync already
+/// covered it, waits for the sync in flight, or becomes the leader and runs the next
+/// sync for everyone who appended before it started
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    cv: Condvar,
+}
+
+impl GroupCommit {
+    /// blocks until the record with sequence number `seq` is on disk.
+    /// `sync` makes the active file durable and returns the sequence number it covered
+    pub fn wait(&self, seq: u64, sync: impl Fn() -> io::Result<u64>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            }
+
+            if state.syncing {
+                state = self.cv.wait(state).unwrap();
+                continue;
+            }
+
+            // become the leader for the next sync
+            state.syncing = true;
+            drop(state);
+            let res = sync();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            self.cv.notify_all();
+
+            // on failure, the waiting writers will retry with a new leader
+            state.synced = state.synced.max(res?);
+        }
+    }
+}
+
+/// background thread that periodically runs a sync until dropped
+#[derive(Debug)]
+pub(crate) struct SyncThread {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SyncThread {
+    pub fn spawn(interval: Duration, sync: impl Fn() -> io::Result<()> + Send + 'static) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+
+        let handle = thread::spawn(move || {
+            let (lock, cv) = &*thread_stop;
+            let mut stopped = lock.lock().unwrap();
+            loop {
+                stopped = cv.wait_timeout(stopped, interval).unwrap().0;
+
+                if let Err(e) = sync() {
+                    warn!("periodic sync failed: {e}");
+                }
+
+                if *stopped {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for SyncThread {
+    fn drop(&mut self) {
+        let (lock, cv) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cv.notify_one();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::fmt;
+
+/// errors surfaced by the storage engine that callers may want to match on.
+/// they are returned wrapped in `anyhow::Error` and can be recovered with
+/// `downcast_ref::<HydraError>()`
+#[derive(Debug, PartialEq, Eq)]
+pub enum HydraError {
+    /// a record read back from disk failed its crc, key or length check
+    Corruption { file_id: usize, offset: u64 },
+    /// the keydir kept on disk couldn't be read or written. the i/o error is its cause
+    KeyDirIo,
+}
+
+impl fmt::Display for HydraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HydraError::Corruption { file_id, offset } => {
+                write!(f, "corrupt record in file {file_id} at offset {offset}")
+            }
+            HydraError::KeyDirIo => write!(f, "failed to read or write the keydir index"),
+        }
+    }
+}
+
+impl std::error::Error for HydraError {}
@@ -0,0 +1,174 @@
+use crate::format::FormatVersion;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// a data file opened for reading
+#[derive(Debug)]
+pub(crate) struct DataFile {
+    pub file: File,
+    pub version: FormatVersion,
+}
+
+/// counters of the file cache
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// number of files currently held open by the cache
+    pub open_files: usize,
+}
+
+#[derive(Debug, Default)]
+struct LruState {
+    // file id -> (file, tick of its last use)
+    files: HashMap<usize, (Arc<DataFile>, u64)>,
+    // tick of last use -> file id, oldest first
+    order: BTreeMap<u64, usize>,
+    tick: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, file_id: usize) -> Option<Arc<DataFile>> {
+        let (file, last_used) = self.files.get_mut(&file_id)?;
+        self.order.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.order.insert(self.tick, file_id);
+        Some(file.clone())
+    }
+
+    fn remove(&mut self, file_id: usize) {
+        if let Some((_, last_used)) = self.files.remove(&file_id) {
+            self.order.remove(&last_used);
+        }
+    }
+}
+
+/// a bounded cache of open data files that evicts the least recently used one.
+/// a capacity of 0 disables caching
+#[derive(Debug, Default)]
+pub(crate) struct FileCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// returns the cached file `file_id`, opening it with `open` on a miss
+    pub fn get_or_open(
+        &self,
+        file_id: usize,
+        open: impl FnOnce() -> Result<DataFile>,
+    ) -> Result<Arc<DataFile>> {
+        if let Some(file) = self.state.lock().unwrap().touch(file_id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(file);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // open outside the lock so that a slow open doesn't block other readers
+        let file = Arc::new(open()?);
+        if self.capacity == 0 {
+            return Ok(file);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // another reader may have opened it in the meantime
+        if let Some(file) = state.touch(file_id) {
+            return Ok(file);
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.files.insert(file_id, (file.clone(), tick));
+        state.order.insert(tick, file_id);
+
+        while state.files.len() > self.capacity {
+            let (_, evicted) = state.order.pop_first().unwrap();
+            state.files.remove(&evicted);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(file)
+    }
+
+    /// drops the cached handle of `file_id`, if any, so that the next read reopens it
+    pub fn invalidate(&self, file_id: usize) {
+        self.state.lock().unwrap().remove(file_id);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            open_files: self.state.lock().unwrap().files.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::{CacheStats, DataFile, FileCache};
+    use crate::format::FormatVersion;
+
+    fn open(path: &str) -> anyhow::Result<DataFile> {
+        Ok(DataFile {
+            file: File::open(path)?,
+            version: FormatVersion::V0,
+        })
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        fs::write("file_cache_test", b"").unwrap();
+        let cache = FileCache::new(2);
+
+        cache.get_or_open(0, || open("file_cache_test")).unwrap();
+        cache.get_or_open(1, || open("file_cache_test")).unwrap();
+        // 0 becomes the most recently used one, so 1 gets evicted
+        cache.get_or_open(0, || open("file_cache_test")).unwrap();
+        cache.get_or_open(2, || open("file_cache_test")).unwrap();
+        assert_eq!(cache.stats().open_files, 2);
+
+        cache
+            .get_or_open(0, || panic!("0 should be cached"))
+            .unwrap();
+        cache.get_or_open(1, || open("file_cache_test")).unwrap();
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                evictions: 2,
+                open_files: 2
+            }
+        );
+
+        cache.invalidate(1);
+        assert_eq!(cache.stats().open_files, 1);
+        assert!(
+            cache
+                .get_or_open(1, || open("missing_file_cache_test"))
+                .is_err()
+        );
+
+        let _ = fs::remove_file("file_cache_test");
+    }
+}
@@ -0,0 +1,325 @@
+use crate::utils::calc_crc;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+
+/// magic bytes at the start of every versioned data file
+pub const MAGIC: [u8; 4] = *b"HYDB";
+
+/// size of the data file header: magic + version
+pub const FILE_HEADER_SZ: u64 = 5;
+
+/// largest record header of any format version
+pub const MAX_HEADER_SZ: usize = 29;
+
+/// value that marked a deleted key in `V0` files
+const V0_TOMBSTONE: &[u8] = b"TOMBSTONE";
+
+/// on-disk layout of a data file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FormatVersion {
+    /// headerless files written before records were typed.
+    /// record: crc + tstamp + ksz + vsz + key + val
+    #[default]
+    V0,
+    /// file header followed by typed records.
+    /// record: crc + type + tstamp + ksz + vsz + key + val
+    V1,
+    /// same as `V1` but with a 64-bit tstamp.
+    /// record: crc + type + tstamp (u64) + ksz + vsz + key + val
+    V2,
+    /// same as `V2` plus the time (in millis) at which the record expires, 0 if never.
+    /// record: crc + type + tstamp (u64) + expiry (u64) + ksz + vsz + key + val
+    V3,
+}
+
+impl FormatVersion {
+    /// the version new data files are written in
+    pub const CURRENT: FormatVersion = FormatVersion::V3;
+
+    /// size of a record header in this version
+    pub fn header_sz(self) -> usize {
+        match self {
+            FormatVersion::V0 => 16,
+            FormatVersion::V1 => 17,
+            FormatVersion::V2 => 21,
+            FormatVersion::V3 => 29,
+        }
+    }
+
+    /// size of a tstamp in this version. older versions stored a truncated u32
+    pub fn tstamp_sz(self) -> usize {
+        match self {
+            FormatVersion::V0 | FormatVersion::V1 => 4,
+            FormatVersion::V2 | FormatVersion::V3 => 8,
+        }
+    }
+
+    /// whether records in this version carry an expiry
+    pub fn has_expiry(self) -> bool {
+        self == FormatVersion::V3
+    }
+
+    /// offset of the first record in a file of this version
+    pub fn data_start(self) -> u64 {
+        match self {
+            FormatVersion::V0 => 0,
+            FormatVersion::V1 | FormatVersion::V2 | FormatVersion::V3 => FILE_HEADER_SZ,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(FormatVersion::V1),
+            2 => Some(FormatVersion::V2),
+            3 => Some(FormatVersion::V3),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            FormatVersion::V0 => 0,
+            FormatVersion::V1 => 1,
+            FormatVersion::V2 => 2,
+            FormatVersion::V3 => 3,
+        }
+    }
+}
+
+/// kind of a data file record
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordType {
+    #[default]
+    Put = 0,
+    /// marks the key as deleted. the value is empty
+    Delete = 1,
+    /// opens a write batch. key & value are empty
+    BatchBegin = 2,
+    /// closes a write batch. key & value are empty
+    BatchCommit = 3,
+}
+
+impl TryFrom<u8> for RecordType {
+    type Error = io::Error;
+
+    fn try_from(b: u8) -> Result<Self, Self::Error> {
+        match b {
+            0 => Ok(RecordType::Put),
+            1 => Ok(RecordType::Delete),
+            2 => Ok(RecordType::BatchBegin),
+            3 => Ok(RecordType::BatchCommit),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown record type {b}"),
+            )),
+        }
+    }
+}
+
+/// the header of a data file record
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecordHeader {
+    pub crc: u32,
+    pub rtype: RecordType,
+    pub tstamp: u64,
+    /// time (in millis) at which the record expires, 0 if never
+    pub expiry: u64,
+    pub ksz: u32,
+    pub vsz: u32,
+}
+
+impl RecordHeader {
+    /// decodes a header laid out as per `version` from `buf`
+    pub fn decode(version: FormatVersion, buf: &[u8]) -> io::Result<Self> {
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+
+        match version {
+            FormatVersion::V0 => Ok(Self {
+                crc: u32_at(0),
+                rtype: RecordType::Put,
+                tstamp: u32_at(4) as u64,
+                expiry: 0,
+                ksz: u32_at(8),
+                vsz: u32_at(12),
+            }),
+            FormatVersion::V1 => Ok(Self {
+                crc: u32_at(0),
+                rtype: buf[4].try_into()?,
+                tstamp: u32_at(5) as u64,
+                expiry: 0,
+                ksz: u32_at(9),
+                vsz: u32_at(13),
+            }),
+            FormatVersion::V2 => Ok(Self {
+                crc: u32_at(0),
+                rtype: buf[4].try_into()?,
+                tstamp: u64_at(5),
+                expiry: 0,
+                ksz: u32_at(13),
+                vsz: u32_at(17),
+            }),
+            FormatVersion::V3 => Ok(Self {
+                crc: u32_at(0),
+                rtype: buf[4].try_into()?,
+                tstamp: u64_at(5),
+                expiry: u64_at(13),
+                ksz: u32_at(21),
+                vsz: u32_at(25),
+            }),
+        }
+    }
+
+    /// appends the header laid out as per `version`, minus the crc, to `out`
+    fn encode_without_crc(&self, version: FormatVersion, out: &mut Vec<u8>) {
+        if version != FormatVersion::V0 {
+            out.push(self.rtype as u8);
+        }
+        match version {
+            FormatVersion::V0 | FormatVersion::V1 => {
+                out.extend_from_slice(&(self.tstamp as u32).to_be_bytes())
+            }
+            FormatVersion::V2 | FormatVersion::V3 => {
+                out.extend_from_slice(&self.tstamp.to_be_bytes())
+            }
+        }
+        if version.has_expiry() {
+            out.extend_from_slice(&self.expiry.to_be_bytes());
+        }
+        out.extend_from_slice(&self.ksz.to_be_bytes());
+        out.extend_from_slice(&self.vsz.to_be_bytes());
+    }
+
+    /// computes the crc of a record with this header, key & value the way `version` does
+    pub fn calc_crc(&self, version: FormatVersion, k: &[u8], v: &[u8]) -> u32 {
+        let mut buf = Vec::with_capacity(MAX_HEADER_SZ);
+        self.encode_without_crc(version, &mut buf);
+        calc_crc(&buf, k, v)
+    }
+}
+
+/// fixes up the type of a record decoded from a `V0` file, where deletes were encoded
+/// as a special value. `V0` files never held batches, so everything else is a put
+pub fn v0_record_type(val: &[u8]) -> RecordType {
+    if val == V0_TOMBSTONE {
+        RecordType::Delete
+    } else {
+        RecordType::Put
+    }
+}
+
+/// appends a record of the given type in the current format to `out`.
+/// `expiry` is the time (in millis) at which the record expires, 0 if never
+pub fn encode_record(
+    rtype: RecordType,
+    tstamp: u64,
+    expiry: u64,
+    k: &[u8],
+    v: &[u8],
+    out: &mut Vec<u8>,
+) {
+    let mut header = RecordHeader {
+        crc: 0,
+        rtype,
+        tstamp,
+        expiry,
+        ksz: k.len() as u32,
+        vsz: v.len() as u32,
+    };
+    header.crc = header.calc_crc(FormatVersion::CURRENT, k, v);
+
+    out.extend_from_slice(&header.crc.to_be_bytes());
+    header.encode_without_crc(FormatVersion::CURRENT, out);
+    out.extend_from_slice(k);
+    out.extend_from_slice(v);
+}
+
+/// returns the header that starts a data file in the current format
+pub fn file_header() -> [u8; FILE_HEADER_SZ as usize] {
+    let mut header = [0; FILE_HEADER_SZ as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4] = FormatVersion::CURRENT.to_byte();
+    header
+}
+
+/// reads the format version of a data or hint file from its header.
+/// files without a (complete) header are `V0`
+pub fn read_version(file: &File) -> io::Result<FormatVersion> {
+    let mut header = [0; FILE_HEADER_SZ as usize];
+    match file.read_exact_at(&mut header, 0) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(FormatVersion::V0),
+        Err(e) => return Err(e),
+    }
+
+    if header[..4] != MAGIC {
+        return Ok(FormatVersion::V0);
+    }
+
+    FormatVersion::from_byte(header[4]).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported data file version {}", header[4]),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use crate::format::{
+        FormatVersion, RecordHeader, RecordType, encode_record, file_header, read_version,
+        v0_record_type,
+    };
+
+    #[test]
+    fn test_record_header_roundtrip() {
+        let mut buf = vec![];
+        // a tstamp that doesn't fit in 32 bits
+        let tstamp = u32::MAX as u64 + 7;
+        encode_record(RecordType::Put, tstamp, tstamp + 1, b"abhi", b"", &mut buf);
+        assert_eq!(buf.len(), FormatVersion::V3.header_sz() + 4);
+
+        let header = RecordHeader::decode(FormatVersion::V3, &buf).unwrap();
+        assert_eq!(header.rtype, RecordType::Put);
+        assert_eq!(header.tstamp, tstamp);
+        assert_eq!(header.expiry, tstamp + 1);
+        assert_eq!(header.ksz, 4);
+        assert_eq!(header.vsz, 0);
+        assert_eq!(header.calc_crc(FormatVersion::V3, b"abhi", b""), header.crc);
+
+        // unknown record types are rejected
+        buf[4] = 42;
+        assert!(RecordHeader::decode(FormatVersion::V3, &buf).is_err());
+    }
+
+    #[test]
+    fn test_v0_record_type() {
+        assert_eq!(v0_record_type(b"TOMBSTONE"), RecordType::Delete);
+        assert_eq!(v0_record_type(b"rust"), RecordType::Put);
+        // no v0 file ever held batches, so these are plain values
+        assert_eq!(v0_record_type(b"BATCH_BEGIN"), RecordType::Put);
+        assert_eq!(v0_record_type(b"BATCH_COMMIT"), RecordType::Put);
+    }
+
+    #[test]
+    fn test_read_version() {
+        let legacy = File::open("./test/0").unwrap();
+        assert_eq!(read_version(&legacy).unwrap(), FormatVersion::V0);
+
+        fs::write("read_version_test", file_header()).unwrap();
+        let file = File::open("read_version_test").unwrap();
+        assert_eq!(read_version(&file).unwrap(), FormatVersion::CURRENT);
+
+        let mut header = file_header();
+        header[4] = 99;
+        fs::write("read_version_test", header).unwrap();
+        let file = File::open("read_version_test").unwrap();
+        assert!(read_version(&file).is_err());
+
+        let _ = fs::remove_file("read_version_test");
+    }
+}
@@ -0,0 +1,220 @@
+use crate::key_dir::{Cursor, KeyDir, KeyDirEntry, is_empty_range};
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// the entries that keys had when a view was frozen, recorded as the keys change &
+/// kept by the part of the keydir they are in, in key order. it isn't capped: it holds
+/// an entry for every key written while the view is around, & is only freed when the
+/// view is dropped
+#[derive(Debug, Default)]
+pub(crate) struct Changes(Mutex<BTreeMap<usize, BTreeMap<Bytes, Option<KeyDirEntry>>>>);
+
+impl Changes {
+    /// records `entry` as what `k` in `part` had, unless it changed before
+    pub fn record(&self, part: usize, k: &[u8], entry: &Option<KeyDirEntry>) {
+        let mut changes = self.0.lock().unwrap();
+        let changes = changes.entry(part).or_default();
+        if !changes.contains_key(k) {
+            changes.insert(Bytes::copy_from_slice(k), entry.clone());
+        }
+    }
+
+    fn get(&self, part: usize, k: &[u8]) -> Option<Option<KeyDirEntry>> {
+        self.0.lock().unwrap().get(&part)?.get(k).cloned()
+    }
+
+    /// the recorded keys of `part` after `after` & up to `end`, along with what they had
+    fn range(
+        &self,
+        part: usize,
+        after: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Vec<(Bytes, Option<KeyDirEntry>)> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let end = end.map_or(Bound::Unbounded, Bound::Included);
+        let changes = self.0.lock().unwrap();
+        match changes.get(&part) {
+            Some(changes) if !is_empty_range(start, end) => changes
+                .range::<[u8], _>((start, end))
+                .map(|(k, entry)| (k.clone(), entry.clone()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// a view of a keydir as it was when it was frozen. it's copy-on-write: a key that
+/// changes since leaves its old entry with the view, so the view costs memory only
+/// for what is written while it's around
+#[derive(Debug)]
+pub struct FrozenKeyDir<'a> {
+    key_dir: &'a KeyDir,
+    changes: Arc<Changes>,
+}
+
+impl<'a> FrozenKeyDir<'a> {
+    pub(crate) fn new(key_dir: &'a KeyDir, changes: Arc<Changes>) -> Self {
+        Self { key_dir, changes }
+    }
+
+    /// gets the entry the given key `k` had when the view was frozen
+    pub fn get(&self, k: impl AsRef<[u8]>) -> Result<Option<KeyDirEntry>> {
+        let k = k.as_ref();
+        // the keydir goes first. a change it shows was recorded before it was made
+        let cur = self.key_dir.get(k)?;
+        Ok(self.changes.get(self.key_dir.part_of(k), k).unwrap_or(cur))
+    }
+
+    /// iterates over the keys the view holds & their entries lazily
+    pub fn iter(&self) -> FrozenIter<'_> {
+        FrozenIter {
+            frozen: self,
+            part: 0,
+            after: None,
+            page: VecDeque::new(),
+        }
+    }
+}
+
+impl Drop for FrozenKeyDir<'_> {
+    fn drop(&mut self) {
+        self.key_dir.thaw(&self.changes);
+    }
+}
+
+/// a lazy iterator over a frozen view of a keydir. the keydir is walked a page at a
+/// time, & every page is patched with the changes recorded for the keys it spans
+#[derive(Debug)]
+pub struct FrozenIter<'a> {
+    frozen: &'a FrozenKeyDir<'a>,
+    // the part being walked & the last key of it looked up, if any
+    part: usize,
+    after: Option<Bytes>,
+    page: VecDeque<(Bytes, KeyDirEntry)>,
+}
+
+impl FrozenIter<'_> {
+    /// looks up the next page of the view. returns false at the end of the view
+    fn fill(&mut self) -> Result<bool> {
+        let FrozenKeyDir { key_dir, changes } = self.frozen;
+        let limit = key_dir.page_size();
+        while self.part < key_dir.parts() {
+            let cursor = Cursor {
+                part: self.part,
+                key: self.after.clone(),
+            };
+            let (part, mut found) = key_dir.page(&cursor, limit)?;
+            // the part has no keys left now, but it may have had some when frozen
+            if part != self.part {
+                found.clear();
+            }
+
+            // the page spans the keys of the part up to its last one, or all the rest if
+            // it wasn't full
+            let end = match found.last() {
+                Some((last, _)) if found.len() == limit => Some(last.clone()),
+                _ => None,
+            };
+            let mut page: BTreeMap<_, _> = changes
+                .range(self.part, self.after.as_deref(), end.as_deref())
+                .into_iter()
+                .collect();
+            for (k, entry) in found {
+                page.entry(k).or_insert(Some(entry));
+            }
+
+            match end {
+                Some(end) => self.after = Some(end),
+                None => {
+                    self.part += 1;
+                    self.after = None;
+                }
+            }
+
+            // keys that were written since the view was frozen have nothing to show
+            self.page
+                .extend(page.into_iter().filter_map(|(k, entry)| Some((k, entry?))));
+            if !self.page.is_empty() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Iterator for FrozenIter<'_> {
+    type Item = Result<(Bytes, KeyDirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() {
+            match self.fill() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use crate::key_dir::{KeyDir, KeyDirEntry, KeyDirKind, PAGE};
+
+    #[test]
+    fn test_frozen_key_dir() {
+        for kind in [
+            KeyDirKind::Standard,
+            KeyDirKind::Compact,
+            KeyDirKind::Ordered,
+        ] {
+            let store = KeyDir::open(kind, ".").unwrap();
+            // more than a page of keys in every part so that changes land in different
+            // pages of it
+            let n = (PAGE * 2 * store.parts()) as u64;
+            for i in 0..n {
+                store
+                    .put(format!("key{i:05}"), KeyDirEntry::new(1, 5, i, 0, 0))
+                    .unwrap();
+            }
+
+            let frozen = store.freeze();
+            for i in (0..n).step_by(3) {
+                store.del(format!("key{i:05}")).unwrap();
+            }
+            for i in (1..n).step_by(3) {
+                store
+                    .put(format!("key{i:05}"), KeyDirEntry::new(2, 5, i, 0, 0))
+                    .unwrap();
+            }
+            store.put("new", KeyDirEntry::new(2, 5, 0, 0, 0)).unwrap();
+            assert!(
+                store
+                    .compare_and_set("key00002", 1, 2, KeyDirEntry::new(3, 5, 2, 0, 0))
+                    .unwrap()
+            );
+
+            assert!(frozen.get("new").unwrap().is_none());
+            assert_eq!(frozen.get("key00000").unwrap().unwrap().file_id, 1);
+            assert_eq!(frozen.get("key00001").unwrap().unwrap().file_id, 1);
+            assert_eq!(frozen.get("key00002").unwrap().unwrap().file_id, 1);
+            assert_eq!(store.get("key00002").unwrap().unwrap().file_id, 3);
+
+            let seen: BTreeMap<_, _> = frozen.iter().map(Result::unwrap).collect();
+            assert_eq!(seen.len(), n as usize);
+            assert!(seen.values().all(|entry| entry.file_id == 1));
+
+            // changes stop being recorded once the view is gone
+            let changes = frozen.changes.clone();
+            drop(frozen);
+            assert_eq!(Arc::strong_count(&changes), 1);
+            assert_eq!(store.len(), n as usize * 2 / 3 + 1);
+        }
+    }
+}
@@ -1,17 +1,21 @@
+use crate::data_file_iter::read_header;
+use crate::format::{FormatVersion, read_version};
 use anyhow::Result;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 /// iterates over a hint file
 pub struct HintFileIterator {
-    buf: [u8; 20], // 4 crc + 4 tstamp + 4 vsz + 8 val_pos
+    buf: [u8; 8 + 8 + 4 + 4 + 8], // tstamp + expiry + ksz + vsz + val_pos
     reader: BufReader<File>,
+    version: FormatVersion,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct HintFileEntry {
-    pub tstamp: u32,
+    pub tstamp: u64,
+    pub expiry: u64,
     pub ksz: u32,
     pub vsz: u32,
     pub key: Vec<u8>,
@@ -22,11 +26,15 @@ impl HintFileIterator {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
         let path: PathBuf = path.as_ref().to_path_buf();
 
-        let file = File::options().read(true).open(&path)?;
+        let mut file = File::options().read(true).open(&path)?;
+        // hint files share the header of data files. headerless ones have u32 tstamps
+        let version = read_version(&file)?;
+        file.seek(SeekFrom::Start(version.data_start()))?;
 
         Ok(Self {
-            buf: [0; 4 + 4 + 4 + 8],
+            buf: [0; 8 + 8 + 4 + 4 + 8],
             reader: BufReader::new(file),
+            version,
         })
     }
 }
@@ -35,45 +43,47 @@ impl Iterator for HintFileIterator {
     type Item = Result<HintFileEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(size) = self.reader.read(&mut self.buf)
-            && size != 0
-        {
-            let mut i = 0;
-            let mut j = 3;
-
-            let tstamp = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
-            let ksz = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 4;
-            let vsz = u32::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-            i = j + 1;
-            j += 8;
-            let val_pos = u64::from_be_bytes(self.buf[i..=j].try_into().unwrap());
-
-            let mut key = vec![0; ksz as usize];
-            if let Err(e) = self.reader.read_exact(&mut key) {
-                return Some(Err(e.into()));
-            }
-
-            let entry = HintFileEntry {
-                tstamp,
-                ksz,
-                vsz,
-                key,
-                val_pos,
-            };
+        let tstamp_sz = self.version.tstamp_sz();
+        let expiry_sz = if self.version.has_expiry() { 8 } else { 0 };
+        let buf = &mut self.buf[..tstamp_sz + expiry_sz + 4 + 4 + 8];
+        match read_header(&mut self.reader, buf) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
 
-            Some(Ok(entry))
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+        let tstamp = if tstamp_sz == 8 {
+            u64_at(0)
         } else {
-            None
+            u32_at(0) as u64
+        };
+        let expiry = if expiry_sz == 8 { u64_at(tstamp_sz) } else { 0 };
+        let i = tstamp_sz + expiry_sz;
+        let ksz = u32_at(i);
+        let vsz = u32_at(i + 4);
+        let val_pos = u64_at(i + 8);
+
+        let mut key = vec![0; ksz as usize];
+        if let Err(e) = self.reader.read_exact(&mut key) {
+            return Some(Err(e.into()));
         }
+
+        Some(Ok(HintFileEntry {
+            tstamp,
+            expiry,
+            ksz,
+            vsz,
+            key,
+            val_pos,
+        }))
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::format::file_header;
     use crate::hint_file_iter::{HintFileEntry, HintFileIterator};
     use std::fs::{self, File};
     use std::io::Write;
@@ -83,6 +93,7 @@ mod test {
         let mut file = File::options()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("hint_file_iter_test")
             .unwrap();
 
@@ -101,6 +112,7 @@ mod test {
             entry,
             HintFileEntry {
                 tstamp: 1,
+                expiry: 0,
                 ksz: 4,
                 vsz: 4,
                 key: b"abhi".to_vec(),
@@ -110,4 +122,34 @@ mod test {
 
         let _ = fs::remove_file("hint_file_iter_test");
     }
+
+    #[test]
+    fn test_versioned_hint_iter() {
+        let tstamp = u32::MAX as u64 + 1;
+
+        let mut data = file_header().to_vec();
+        data.extend_from_slice(&tstamp.to_be_bytes()); // ts
+        data.extend_from_slice(&(tstamp + 1).to_be_bytes()); // expiry
+        data.extend_from_slice(&4u32.to_be_bytes()); // ksz
+        data.extend_from_slice(&4u32.to_be_bytes()); // vsz
+        data.extend_from_slice(&30u64.to_be_bytes()); // v_pos
+        data.extend_from_slice(b"abhi"); // key
+        fs::write("versioned_hint_file_iter_test", &data).unwrap();
+
+        let mut iter = HintFileIterator::new("versioned_hint_file_iter_test").unwrap();
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            HintFileEntry {
+                tstamp,
+                expiry: tstamp + 1,
+                ksz: 4,
+                vsz: 4,
+                key: b"abhi".to_vec(),
+                val_pos: 30
+            }
+        );
+        assert!(iter.next().is_none());
+
+        let _ = fs::remove_file("versioned_hint_file_iter_test");
+    }
 }
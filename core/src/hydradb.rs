@@ -1,67 +1,138 @@
-use crate::builder::HydraDBBuilder;
+use crate::background::BackgroundThread;
+pub use crate::builder::HydraDBBuilder;
+use crate::compaction::{
+    CompactionFilterHandle, CompactionPolicy, FileStats, FilterDecision, Fragmentation, record_sz,
+};
 use crate::data_file_iter::{DataFileEntry, OptimizedDataFileIterator};
-use crate::key_dir::{KeyDir, KeyDirEntry};
+use crate::durability::{Durability, GroupCommit, SyncThread};
+use crate::error::HydraError;
+use crate::file_cache::{CacheStats, DataFile, FileCache};
+use crate::format::{
+    FILE_HEADER_SZ, FormatVersion, RecordHeader, RecordType, encode_record, file_header,
+    read_version,
+};
+use crate::iter::{Iter, Keys};
+use crate::key_dir::{KeyDir, KeyDirEntry, KeyDirKind, KeyDirMemory};
+use crate::keydir_snapshot::KeyDirSnapshot;
+use crate::merge_journal::MergeJournal;
+use crate::merge_output::MergeOutput;
+use crate::merge_progress::{MergeProgress, MergeProgressTracker};
+use crate::multi_get::plan_reads;
+use crate::rate_limiter::RateLimiter;
 use crate::restore::*;
-use crate::utils::calc_crc;
+use crate::scan::Scan;
+use crate::snapshot::Snapshot;
+use crate::utils::{data_file_ids, now_millis};
+use crate::write_batch::{BatchOp, WriteBatch};
 use anyhow::Result;
 use bytes::Bytes;
-use core::str;
-use dashmap::DashMap;
-use log::debug;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::fs;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::ops::{Bound, Range, RangeBounds};
 use std::os::unix::fs::FileExt;
 use std::path::Path;
-use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
 use std::{
     fs::{DirBuilder, File},
-    time::{SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
 
-/// returns a raw db entry to persist from the given data
-#[inline]
-fn to_db_entry(crc: u32, tstamp: u32, k: &[u8], v: &[u8]) -> Vec<u8> {
-    // crc + tstamp + ksz + vsz + key + val
-    let mut o = Vec::with_capacity(4 + 4 + 4 + 4 + k.len() + v.len());
-
-    let kl = k.len() as u32;
-    let vl = v.len() as u32;
-
-    o.extend_from_slice(&crc.to_be_bytes());
-    o.extend_from_slice(&tstamp.to_be_bytes());
-    o.extend_from_slice(&kl.to_be_bytes());
-    o.extend_from_slice(&vl.to_be_bytes());
-    o.extend_from_slice(k);
-    o.extend_from_slice(v);
-    o
+/// a record to be appended to the active file
+#[derive(Debug, Clone)]
+struct Record<'a> {
+    rtype: RecordType,
+    key: Bytes,
+    val: &'a [u8],
+    expiry: u64,
 }
 
-#[inline]
-fn to_hint_entry(tstamp: u32, k: &[u8], v: &[u8], val_pos: u64) -> Vec<u8> {
-    // tstamp + ksz + vsz + val_pos + key
-    let mut o = Vec::with_capacity(4 + 4 + 4 + 8 + k.len());
+impl<'a> Record<'a> {
+    fn put(key: Bytes, val: &'a [u8], expiry: u64) -> Self {
+        Self {
+            rtype: RecordType::Put,
+            key,
+            val,
+            expiry,
+        }
+    }
 
-    let kl = k.len() as u32;
-    let vl = v.len() as u32;
+    fn del(key: Bytes) -> Self {
+        Self {
+            rtype: RecordType::Delete,
+            key,
+            val: b"",
+            expiry: 0,
+        }
+    }
 
-    o.extend_from_slice(&tstamp.to_be_bytes());
-    o.extend_from_slice(&kl.to_be_bytes());
-    o.extend_from_slice(&vl.to_be_bytes());
-    o.extend_from_slice(&val_pos.to_be_bytes());
-    o.extend_from_slice(k);
-    o
+    fn marker(rtype: RecordType) -> Self {
+        Self {
+            rtype,
+            key: Bytes::new(),
+            val: b"",
+            expiry: 0,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 struct WriterState {
     writer: Option<BufWriter<File>>,
+    // a second handle to the active file so that it can be synced
+    // without holding the writer lock
+    sync_handle: Option<Arc<File>>,
     // tracks the val positions in a data file
     // so that we avoid expensive seek operations to calculate them
     last_val_offset: u64,
     cur_file_size: u64,
+    // number of records appended so far, used by group commit
+    seq: u64,
+    // set when the active file is in an older format. the writer
+    // moves on to a new file before appending anything to it
+    needs_roll: bool,
+}
+
+impl WriterState {
+    fn new(file: File, cur_file_size: u64, seq: u64) -> Result<Self> {
+        Ok(Self {
+            sync_handle: Some(Arc::new(file.try_clone()?)),
+            writer: Some(BufWriter::new(file)),
+            last_val_offset: cur_file_size,
+            cur_file_size,
+            seq,
+            needs_roll: false,
+        })
+    }
+
+    /// appends a raw entry to the active file & hands it to the os.
+    /// on failure the file is cut back to its previous size so that a
+    /// partially written entry doesn't shift the offsets of later ones
+    fn append(&mut self, entry: &[u8]) -> Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        if let Err(e) = writer.write_all(entry).and_then(|_| writer.flush()) {
+            let (file, _) = self.writer.take().unwrap().into_parts();
+            let res = file.set_len(self.cur_file_size);
+            self.writer = Some(BufWriter::new(file));
+            res?;
+            return Err(e.into());
+        }
+
+        self.last_val_offset += entry.len() as u64;
+        self.cur_file_size += entry.len() as u64;
+        self.seq += 1;
+        Ok(())
+    }
+
+    /// forces the active file to disk
+    fn sync(&self) -> io::Result<()> {
+        self.sync_handle.as_ref().unwrap().sync_data()
+    }
 }
 
 /// the main bitcask storage engine
@@ -81,225 +152,844 @@ pub struct HydraDB {
 
     /// file writer
     #[serde(skip)]
-    writer: Mutex<WriterState>,
+    writer: Arc<Mutex<WriterState>>,
+
+    /// when appended records get synced to disk
+    #[serde(skip)]
+    durability: Durability,
+
+    /// shares fsyncs between concurrent writers in `Durability::GroupCommit` mode
+    #[serde(skip)]
+    group_commit: GroupCommit,
+
+    /// syncs the active file in the background in `Durability::Interval` mode.
+    /// only held so that the thread stops when the db is dropped
+    #[serde(skip)]
+    _sync_thread: Option<SyncThread>,
 
     /// for caching files during reads
     #[serde(skip)]
-    file_cache: DashMap<usize, Arc<File>>,
+    file_cache: FileCache,
+
+    /// serializes merges
+    #[serde(skip)]
+    merge_lock: Mutex<()>,
+
+    /// odd while a merge is publishing its output & repointing the keydir at it.
+    /// lets a read that raced with a merge tell a moved record from a corrupt one
+    #[serde(skip)]
+    merge_epoch: AtomicU64,
+
+    /// live & dead bytes of every data file
+    #[serde(skip)]
+    fragmentation: Fragmentation,
+
+    /// when the background compaction thread merges
+    #[serde(skip)]
+    compaction: CompactionPolicy,
+
+    /// decides what happens to the live records during a merge
+    #[serde(skip)]
+    compaction_filter: CompactionFilterHandle,
+
+    /// the background compaction & snapshot threads, once started
+    #[serde(skip)]
+    background: Mutex<Vec<BackgroundThread>>,
+
+    /// how often the keydir gets persisted, if at all. it also is on a clean shutdown
+    #[serde(skip)]
+    keydir_snapshot: Option<Duration>,
+
+    /// how the keydir was restored when the db was opened
+    #[serde(skip)]
+    restore_stats: RestoreStats,
+
+    /// throttles the reads & writes of merges
+    #[serde(skip)]
+    merge_limiter: Arc<RateLimiter>,
+
+    /// how far along the running merge is
+    #[serde(skip)]
+    merge_progress: MergeProgressTracker,
+}
+
+impl Drop for HydraDB {
+    fn drop(&mut self) {
+        // a keydir on disk is only reused by the next open if it's told where it's up to
+        if (self.keydir_snapshot.is_some() || self.key_dir.is_durable())
+            && let Err(e) = self.save_keydir_snapshot()
+        {
+            warn!("./{}: failed to save keydir snapshot: {e}", self.cur_cask);
+        }
+    }
+}
+
+/// bumps the merge epoch when created & again when dropped
+struct PublishGuard<'a>(&'a AtomicU64);
+
+impl<'a> PublishGuard<'a> {
+    fn new(epoch: &'a AtomicU64) -> Self {
+        epoch.fetch_add(1, Ordering::AcqRel);
+        Self(epoch)
+    }
+}
+
+impl Drop for PublishGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::AcqRel);
+    }
 }
 
 impl HydraDB {
     /// creates an instance of `HydraDB` with the given `namespace`
+    #[allow(clippy::too_many_arguments)]
     pub fn new<T: Into<String> + Debug>(
         namespace: T,
         max_file_size_threshold: u64,
         cache_size: usize,
+        durability: Durability,
+        compaction: CompactionPolicy,
+        merge_rate_limit: u64,
+        compaction_filter: CompactionFilterHandle,
+        keydir_snapshot: Option<Duration>,
+        restore_threads: usize,
+        restore_rate_limit: u64,
+        keydir: KeyDirKind,
     ) -> Result<Self> {
         let namespace = namespace.into();
 
-        let cur_id;
-        let cur_file_size;
-        let last_val_offset;
-
-        if !fs::exists(format!("./{namespace}"))? {
+        let cur_id = if !fs::exists(format!("./{namespace}"))? {
             let dir_builder = DirBuilder::new();
             dir_builder.create(format!("./{}", &namespace))?;
-            cur_id = 0;
-            cur_file_size = 0;
-            last_val_offset = 0;
+            0
         } else {
-            let mut mx = 0;
-            for entry in fs::read_dir(format!("./{}", &namespace))? {
-                let entry = entry?;
-                let path = entry.path();
-
-                if path.is_file()
-                    && let Some(path) = path.file_name()
-                    && let Some(path) = path.to_str()
-                    && path != "hint"
-                    && path != "temp"
-                {
-                    debug!("path is {path}");
-                    mx = std::cmp::max(mx, path.parse::<usize>()?)
-                }
-            }
+            // a crash during a merge may leave its outputs half published
+            MergeJournal::recover(&namespace)?;
 
-            cur_id = mx;
-
-            let path = format!("./{namespace}/{cur_id}");
-            cur_file_size = if Path::new(&path).exists() {
-                fs::metadata(path)?.len()
-            } else {
-                0
-            };
-            last_val_offset = cur_file_size;
-        }
+            data_file_ids(format!("./{namespace}"))?
+                .last()
+                .copied()
+                .unwrap_or(0)
+        };
+        debug!("active file is {cur_id}");
 
         let file = File::options()
             .create(true)
+            .read(true)
             .append(true)
             .open(format!("./{}/{}", namespace, cur_id))?;
 
-        let mut db = Self {
+        let mut key_dir = KeyDir::open(keydir, format!("./{namespace}"))?;
+        let restore_limiter = RateLimiter::new(restore_rate_limit);
+        let (valid_len, restore_stats) = Self::build_key_dir(
+            &namespace,
+            cur_id,
+            restore_threads,
+            &restore_limiter,
+            &mut key_dir,
+        )?;
+
+        // a crash in the middle of a write leaves a partial record at the end of the
+        // active file. cut it off so that new records start at a valid offset
+        let file_len = file.metadata()?.len();
+        if valid_len < file_len {
+            warn!(
+                "./{namespace}/{cur_id}: truncating {} bytes of torn data at offset {valid_len}",
+                file_len - valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        // never append records to a file in an older format. the header of an
+        // empty file gets written along with its first record
+        let needs_roll = valid_len > 0 && read_version(&file)? != FormatVersion::CURRENT;
+        let fragmentation = Self::build_file_stats(&namespace, cur_id, valid_len, &key_dir)?;
+
+        let mut writer = WriterState::new(file, valid_len, 0)?;
+        writer.needs_roll = needs_roll;
+        let writer = Arc::new(Mutex::new(writer));
+
+        let sync_thread = if let Durability::Interval(ms) = durability {
+            let writer = writer.clone();
+            Some(SyncThread::spawn(Duration::from_millis(ms), move || {
+                let handle = writer.lock().unwrap().sync_handle.clone();
+                handle.unwrap().sync_data()
+            }))
+        } else {
+            None
+        };
+
+        Ok(Self {
             cur_cask: namespace,
             cur_id: cur_id.into(),
-            key_dir: KeyDir::new(),
+            key_dir,
             max_file_size_threshold,
-            writer: Mutex::new(WriterState {
-                writer: Some(BufWriter::new(file)),
-                last_val_offset,
-                cur_file_size,
-            }),
-            file_cache: DashMap::with_capacity(cache_size),
-        };
+            writer,
+            durability,
+            group_commit: GroupCommit::default(),
+            _sync_thread: sync_thread,
+            file_cache: FileCache::new(cache_size),
+            merge_lock: Mutex::new(()),
+            merge_epoch: AtomicU64::new(0),
+            fragmentation,
+            compaction,
+            compaction_filter,
+            background: Mutex::new(vec![]),
+            keydir_snapshot,
+            restore_stats,
+            merge_limiter: Arc::new(RateLimiter::new(merge_rate_limit)),
+            merge_progress: MergeProgressTracker::default(),
+        })
+    }
+
+    /// works out the live & dead bytes of every data file. the live records are the
+    /// ones the keydir points at & everything else in a file is dead
+    fn build_file_stats(
+        cask: &str,
+        cur_id: usize,
+        active_len: u64,
+        key_dir: &KeyDir,
+    ) -> Result<Fragmentation> {
+        let mut files = BTreeMap::new();
+        for file_id in data_file_ids(format!("./{cask}"))? {
+            let file = File::open(format!("./{cask}/{file_id}"))?;
+            let len = if file_id == cur_id {
+                active_len
+            } else {
+                file.metadata()?.len()
+            };
+            // an empty file gets its header along with its first record
+            let version = if len == 0 {
+                FormatVersion::CURRENT
+            } else {
+                read_version(&file)?
+            };
+            files.insert(
+                file_id,
+                (version, len.saturating_sub(version.data_start()), 0),
+            );
+        }
 
-        let _ = db.build_key_dir();
+        key_dir.for_each(|k, entry| {
+            if let Some((version, _, live)) = files.get_mut(&entry.file_id) {
+                *live += record_sz(*version, k.len(), entry.val_sz);
+            }
+        })?;
 
-        Ok(db)
+        let fragmentation = Fragmentation::default();
+        for (file_id, (version, total, live)) in files {
+            let stats = FileStats {
+                live_bytes: live,
+                dead_bytes: total.saturating_sub(live),
+            };
+            fragmentation.track(file_id, version, stats);
+        }
+        Ok(fragmentation)
     }
 
-    /// builds the in-mem store by scanning the data files
-    fn build_key_dir(&mut self) -> Result<()> {
-        let restorer: Box<dyn Restore> = if Path::new(&format!("{}/hint", self.cur_cask)).exists() {
-            Box::new(HintFileRestore)
-        } else {
-            Box::new(DataFileRestore)
+    /// starts the background compaction thread if the db has a compaction policy & the
+    /// snapshot thread if keydir snapshots are on
+    pub(crate) fn start_background_threads(self: &Arc<Self>) {
+        let mut threads = self.background.lock().unwrap();
+        if self.compaction.is_enabled() {
+            threads.push(BackgroundThread::spawn(
+                "compaction",
+                Arc::downgrade(self),
+                self.compaction.interval,
+                |db| db.compact_if_due().map(|_| ()),
+            ));
+        }
+        if let Some(interval) = self.keydir_snapshot {
+            threads.push(BackgroundThread::spawn(
+                "keydir snapshot",
+                Arc::downgrade(self),
+                interval,
+                Self::save_keydir_snapshot,
+            ));
+        }
+    }
+
+    /// persists the keydir so that the next open only replays the records written
+    /// after this point. writes & gets go on meanwhile, only merges wait
+    pub fn save_keydir_snapshot(&self) -> Result<()> {
+        // a merge moves records around, so none may publish until the snapshot is
+        // written. it removes the snapshot before publishing
+        let _merging = self.merge_lock.lock().unwrap();
+
+        // the snapshot covers everything before this point & it has to be on disk, as
+        // the keydir may point at any of it. anything written while the keydir is being
+        // copied gets replayed from here on top of it, which is harmless
+        let (active_id, offset) = {
+            let writer = self.writer.lock().unwrap();
+            if writer.cur_file_size > 0 {
+                writer.sync()?;
+            }
+            (self.cur_id.load(Ordering::Acquire), writer.cur_file_size)
         };
 
-        restorer.restore(
-            "./",
-            &self.cur_cask,
-            self.cur_id.load(std::sync::atomic::Ordering::Relaxed),
-            &mut self.key_dir,
-        )
+        KeyDirSnapshot::new(&self.cur_cask, active_id, offset)?
+            .write(&self.cur_cask, &self.key_dir)?;
+        debug!(
+            "./{}: saved keydir snapshot at {active_id}:{offset}",
+            self.cur_cask
+        );
+        Ok(())
+    }
+
+    /// estimates the memory taken by the keydir
+    pub fn keydir_memory(&self) -> KeyDirMemory {
+        self.key_dir.memory()
+    }
+
+    /// how long restoring the keydir took when the db was opened & how much it read
+    pub fn restore_stats(&self) -> RestoreStats {
+        self.restore_stats
+    }
+
+    /// live & dead bytes of every data file, by file id
+    pub fn file_stats(&self) -> BTreeMap<usize, FileStats> {
+        self.fragmentation.stats()
+    }
+
+    /// limits the i/o of merges to `bytes_per_sec`, 0 for no limit. a running merge
+    /// picks up the new limit right away
+    pub fn set_merge_rate_limit(&self, bytes_per_sec: u64) {
+        self.merge_limiter.set_rate(bytes_per_sec);
+    }
+
+    /// how far along the running merge is, or how the last one ended
+    pub fn merge_progress(&self) -> MergeProgress {
+        self.merge_progress.get()
+    }
+
+    /// merges if the immutable data files cross a threshold of the compaction policy.
+    /// returns whether a merge ran
+    pub fn compact_if_due(&self) -> Result<bool> {
+        let cur_id = self.cur_id.load(Ordering::Acquire);
+        let stats = self.fragmentation.stats();
+        let immutable = stats.range(..cur_id).map(|(_, stats)| stats);
+        if !self.compaction.is_due(immutable, now_millis()) {
+            return Ok(false);
+        }
+
+        debug!("./{}: compaction is due", self.cur_cask);
+        match self.compaction.max_files {
+            Some(max_files) => {
+                let min_dead_ratio = self.compaction.dead_ratio.unwrap_or(0.0);
+                self.merge_fragmented(max_files, min_dead_ratio)?;
+            }
+            None => self.merge()?,
+        }
+        Ok(true)
+    }
+
+    /// builds the in-mem store by replaying every data file in id order on `threads`
+    /// workers, using a file's hint instead when it has one, with their reads throttled
+    /// by `limiter`. a valid keydir snapshot stands in for everything it covers. returns
+    /// the length of the valid data in the active file
+    fn build_key_dir(
+        cask: &str,
+        cur_id: usize,
+        threads: usize,
+        limiter: &RateLimiter,
+        key_dir: &mut KeyDir,
+    ) -> Result<(u64, RestoreStats)> {
+        let ids = data_file_ids(format!("./{cask}"))?;
+
+        // older versions wrote a single `hint` for the merged file. the merged file is
+        // always the oldest one in the cask, so give it that file's name
+        let legacy_hint = format!("./{cask}/hint");
+        if Path::new(&legacy_hint).exists()
+            && let Some(&merged_id) = ids.first()
+            && merged_id != cur_id
+        {
+            fs::rename(&legacy_hint, format!("./{cask}/{merged_id}.hint"))?;
+        }
+
+        // (file id, offset) to replay from
+        let mut start = (0, 0);
+        match KeyDirSnapshot::read(cask, key_dir) {
+            Ok(Some(snapshot)) if snapshot.matches(cask)? => {
+                debug!(
+                    "./{cask}: restoring keydir snapshot at {}:{}",
+                    snapshot.active_id, snapshot.offset
+                );
+                start = (snapshot.active_id, snapshot.offset);
+            }
+            Ok(Some(_)) => {
+                warn!("./{cask}: keydir snapshot is stale, scanning all files");
+                key_dir.clear()?;
+            }
+            // a keydir that kept its entries can't tell what they are up to on its own
+            Ok(None) if key_dir.is_restored() => {
+                warn!("./{cask}: no keydir snapshot for the keydir index, scanning all files");
+                key_dir.clear()?;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("./{cask}: ignoring keydir snapshot: {e}");
+                key_dir.clear()?;
+            }
+        }
+
+        let jobs: Vec<RestoreJob> = ids
+            .into_iter()
+            .filter(|&file_id| file_id >= start.0)
+            .map(|file_id| RestoreJob {
+                file_id,
+                from: if file_id == start.0 { start.1 } else { 0 },
+                use_hint: file_id != cur_id
+                    && file_id != start.0
+                    && Path::new(&format!("./{cask}/{file_id}.hint")).exists(),
+                active: file_id == cur_id,
+            })
+            .collect();
+
+        let (lens, stats) = restore_files(".", cask, &jobs, threads, limiter, key_dir)?;
+        info!(
+            "./{cask}: restored {} keys from {} files ({} bytes) in {:?} on {} threads, {:.1} MB/s",
+            stats.keys,
+            stats.files,
+            stats.bytes,
+            stats.elapsed,
+            stats.threads,
+            stats.bytes_per_sec() / 1_000_000.0
+        );
+
+        let valid_len = jobs
+            .iter()
+            .zip(lens)
+            .find(|(job, _)| job.file_id == cur_id)
+            .map_or(0, |(_, len)| len);
+        Ok((valid_len, stats))
     }
 
+    #[cfg(test)]
     fn get_active_file(&self) -> usize {
         self.cur_id.load(std::sync::atomic::Ordering::Relaxed)
     }
 
     /// gets the value, if present, for the given key `k`
     pub fn get(&self, k: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
-        if let Some(in_mem_entry) = self.key_dir.get(k) {
-            let KeyDirEntry {
-                file_id,
-                val_sz,
-                val_pos,
-                tstamp: _,
-            } = in_mem_entry;
-            // debug!("val_pos is {val_pos} val sz {val_sz}");
-
-            // debug!("reading from ./{}/{}", self.cur_cask, file_id);
-            let file = if let Some(arcd_file) = self.file_cache.get(&file_id) {
-                arcd_file.clone()
-            } else {
-                self.file_cache.insert(
-                    file_id,
-                    Arc::new(
-                        File::options()
-                            .read(true)
-                            .open(format!("./{}/{}", self.cur_cask, file_id))?,
-                    ),
-                );
-                self.file_cache.get(&file_id).unwrap().clone()
+        let k = k.as_ref();
+        loop {
+            let epoch = self.merge_epoch.load(Ordering::Acquire);
+            let Some(in_mem_entry) = self.key_dir.get(k)? else {
+                return Ok(None);
             };
+            if in_mem_entry.is_expired(now_millis()) {
+                return Ok(None);
+            }
+
+            match self.read_value(k, &in_mem_entry) {
+                // a merge published its output while the record was being read, so
+                // the entry may have pointed at a replaced or deleted file. try again
+                Err(_) if epoch % 2 == 1 || self.merge_epoch.load(Ordering::Acquire) != epoch => {
+                    thread::yield_now();
+                }
+                res => return res.map(Some),
+            }
+        }
+    }
+
+    /// gets the values, if present, for all the given `keys`, in the same order. the
+    /// records are read a file at a time in the order they lie in it, and the ones that
+    /// lie close together are read at once
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Bytes>>> {
+        loop {
+            let epoch = self.merge_epoch.load(Ordering::Acquire);
+            match self.multi_get_once(keys) {
+                // a merge published its output while the records were being read. see `get`
+                Err(_) if epoch % 2 == 1 || self.merge_epoch.load(Ordering::Acquire) != epoch => {
+                    thread::yield_now();
+                }
+                res => return res,
+            }
+        }
+    }
 
-            // file.seek(SeekFrom::Start(val_pos))?;
-            // debug!("file pos is {:?}", file.stream_position());
+    fn multi_get_once<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Bytes>>> {
+        let now = now_millis();
+        // (index of the key, its entry) by the file its record is in
+        let mut by_file: BTreeMap<usize, Vec<(usize, KeyDirEntry)>> = BTreeMap::new();
+        for (i, k) in keys.iter().enumerate() {
+            if let Some(entry) = self.key_dir.get(k)?
+                && !entry.is_expired(now)
+            {
+                by_file.entry(entry.file_id).or_default().push((i, entry));
+            }
+        }
+
+        let mut values = vec![None; keys.len()];
+        for (file_id, mut found) in by_file {
+            let data_file = self.open_data_file(file_id)?;
+            let version = data_file.version;
+            found.sort_unstable_by_key(|(_, entry)| entry.val_pos);
+            let spans: Vec<_> = found
+                .iter()
+                .map(|(i, entry)| record_span(version, keys[*i].as_ref(), entry))
+                .collect::<Result<_>>()?;
+
+            for read in plan_reads(&spans) {
+                let buf = read_span(&data_file, file_id, read.span.clone())?;
+                for j in read.records {
+                    let (i, entry) = &found[j];
+                    let start = (spans[j].start - read.span.start) as usize;
+                    let end = (spans[j].end - read.span.start) as usize;
+                    let val =
+                        check_record(version, keys[*i].as_ref(), entry, buf.slice(start..end))?;
+                    values[*i] = Some(val);
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// reads the value of `k` that its keydir entry `entry` points at, looking `k` up
+    /// again if the entry went stale meanwhile. returns `None` if `k` is gone
+    pub(crate) fn read_entry(&self, k: &[u8], entry: &KeyDirEntry) -> Result<Option<Bytes>> {
+        if entry.is_expired(now_millis()) {
+            return Ok(None);
+        }
+        match self.read_value(k, entry) {
+            Ok(val) => Ok(Some(val)),
+            // a merge may have replaced the file the entry points at
+            Err(_) => self.get(k),
+        }
+    }
 
-            let mut v = vec![0; val_sz as usize];
-            file.read_exact_at(&mut v, val_pos)?;
-            // let mut f = file.take(val_sz as u64);
+    /// reads & verifies the value that the keydir entry of `k` points at
+    fn read_value(&self, k: &[u8], in_mem_entry: &KeyDirEntry) -> Result<Bytes> {
+        // debug!("reading from ./{}/{}", self.cur_cask, file_id);
+        let data_file = self.open_data_file(in_mem_entry.file_id)?;
+        read_record(&data_file, k, in_mem_entry)
+    }
 
-            // f.read_to_end(&mut v)?;
-            // debug!("value is {}", str::from_utf8(&v).unwrap());
+    /// returns the data file `file_id`, opened for reading
+    fn open_data_file(&self, file_id: usize) -> Result<Arc<DataFile>> {
+        self.file_cache.get_or_open(file_id, || {
+            let file = File::options()
+                .read(true)
+                .open(format!("./{}/{}", self.cur_cask, file_id))?;
+            let version = read_version(&file)?;
+            Ok(DataFile { file, version })
+        })
+    }
 
-            Ok(Some(v.into()))
-        } else {
-            Ok(None)
+    /// takes a consistent, read-only snapshot of the db. see `Snapshot`
+    pub fn snapshot(&self) -> Result<Snapshot<'_>> {
+        loop {
+            // merges publish under the writer lock, so no merge is halfway through while
+            // the view is frozen
+            let (key_dir, epoch) = {
+                let _writer = self.writer.lock().unwrap();
+                (
+                    self.key_dir.freeze(),
+                    self.merge_epoch.load(Ordering::Acquire),
+                )
+            };
+
+            // the files in the cask when the view was frozen are pinned. they're opened
+            // outside the file cache, which would otherwise evict the files that readers
+            // use. a merge that published before the view was frozen may still remove
+            // its inputs, but the view doesn't point at them
+            let mut files = HashMap::new();
+            for file_id in data_file_ids(format!("./{}", self.cur_cask))? {
+                let file = match File::open(format!("./{}/{}", self.cur_cask, file_id)) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let version = read_version(&file)?;
+                files.insert(file_id, Arc::new(DataFile { file, version }));
+            }
+
+            // a merge that published meanwhile may have swapped a file under an id the
+            // view points at before it was opened. freeze the view again
+            if self.merge_epoch.load(Ordering::Acquire) == epoch {
+                return Ok(Snapshot::new(key_dir, files));
+            }
+            thread::yield_now();
         }
     }
 
     /// puts the given key-value pair under the set namespace
     pub fn put(&self, k: impl Into<Bytes>, v: impl Into<Bytes>) -> Result<()> {
+        self.put_with_expiry(k.into(), v.into(), 0)
+    }
+
+    /// puts the given key-value pair under the set namespace. the key is treated
+    /// as absent once `ttl` has passed
+    pub fn put_with_ttl(
+        &self,
+        k: impl Into<Bytes>,
+        v: impl Into<Bytes>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expiry = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.put_with_expiry(k.into(), v.into(), expiry)
+    }
+
+    fn put_with_expiry(&self, k: Bytes, v: Bytes, expiry: u64) -> Result<()> {
+        self.append_records(&[Record::put(k, &v, expiry)])
+    }
+
+    /// puts the given key-value pair only if the key is absent.
+    /// returns whether the put happened
+    pub fn put_if_absent(&self, k: impl Into<Bytes>, v: impl Into<Bytes>) -> Result<bool> {
+        let k = k.into();
+        let writer = self.writer.lock().unwrap();
+        if self.has_key(&k)? {
+            return Ok(false);
+        }
+
+        self.append_locked(writer, &[Record::put(k, &v.into(), 0)])?;
+        Ok(true)
+    }
+
+    /// overwrites the value of the given key only if the key is present.
+    /// returns whether the put happened
+    pub fn replace_if_exists(&self, k: impl Into<Bytes>, v: impl Into<Bytes>) -> Result<bool> {
+        let k = k.into();
+        let writer = self.writer.lock().unwrap();
+        if !self.has_key(&k)? {
+            return Ok(false);
+        }
+
+        self.append_locked(writer, &[Record::put(k, &v.into(), 0)])?;
+        Ok(true)
+    }
+
+    /// puts `new` for the given key only if its current value is `expected`, where
+    /// `None` expects the key to be absent. returns whether the put happened
+    pub fn compare_and_swap(
+        &self,
+        k: impl Into<Bytes>,
+        expected: Option<&[u8]>,
+        new: impl Into<Bytes>,
+    ) -> Result<bool> {
         let k = k.into();
-        let v = v.into();
+        let writer = self.writer.lock().unwrap();
+        if self.get(&k)?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        self.append_locked(writer, &[Record::put(k, &new.into(), 0)])?;
+        Ok(true)
+    }
 
-        let entry = self.put_with_file_size_check(&k, &v)?;
+    /// makes `new_cur_id` the active file. the caller must hold the writer lock
+    fn roll_locked(&self, writer: &mut WriterState, new_cur_id: usize) -> Result<()> {
+        // records in the old file must not depend on a later sync of the new one
+        if self.durability != Durability::None {
+            writer.sync()?;
+        }
 
-        // then write to im
-        self.key_dir.put(k, entry);
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(format!("./{}/{}", self.cur_cask, new_cur_id))?;
 
+        let seq = writer.seq;
+        *writer = WriterState::new(file, 0, seq)?;
+        self.cur_id.store(new_cur_id, Ordering::Release);
         Ok(())
     }
 
-    fn put_with_file_size_check(&self, k: &[u8], v: &[u8]) -> Result<KeyDirEntry> {
-        // allow only one writer at a time
+    /// takes the id right after the active file for a merged file & moves the writer
+    /// on past it. anything written from now on lands in a later file than the records
+    /// a merge copies into the reserved one, so it still wins on restore
+    fn reserve_file_id(&self) -> Result<usize> {
         let mut writer = self.writer.lock().unwrap();
+        let reserved = self.cur_id.load(Ordering::Relaxed) + 1;
+        self.roll_locked(&mut writer, reserved + 1)?;
+        Ok(reserved)
+    }
+
+    /// appends the given records contiguously to the active file with a single write
+    /// and applies them to the keydir
+    fn append_records(&self, records: &[Record]) -> Result<()> {
+        // allow only one writer at a time
+        let writer = self.writer.lock().unwrap();
+        self.append_locked(writer, records)
+    }
+
+    /// same as `append_records` for a caller that already holds the writer lock, e.g.
+    /// to check a condition against the keydir atomically with the write
+    fn append_locked(&self, mut writer: MutexGuard<WriterState>, records: &[Record]) -> Result<()> {
+        let header_sz = FormatVersion::CURRENT.header_sz();
+        let total_sz: u64 = records
+            .iter()
+            .map(|r| (header_sz + r.key.len() + r.val.len()) as u64)
+            .sum();
 
         debug!("cur file size {}", writer.cur_file_size);
-        let cur_id = if (16u64 + k.len() as u64 + v.len() as u64 + writer.cur_file_size)
-            >= self.max_file_size_threshold
+        // the records never get split across files. a batch bigger than the limit
+        // gets a file of its own
+        let cur_id = if writer.needs_roll
+            || (writer.cur_file_size > FILE_HEADER_SZ
+                && (total_sz + writer.cur_file_size) >= self.max_file_size_threshold)
         {
             // SAFETY: it is safe to use relaxed ordering here since we are locking
             // the writer at the beginning of this method. therefore, everything after
             // will be sequential execution
-            let old_cur_id = self
-                .cur_id
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            let new_cur_id = old_cur_id + 1;
-
-            let file = File::options()
-                .create(true)
-                .append(true)
-                .open(format!("./{}/{}", self.cur_cask, new_cur_id))?;
-
-            *writer = WriterState {
-                writer: Some(BufWriter::new(file)),
-                last_val_offset: 0,
-                cur_file_size: 0,
-            };
+            let new_cur_id = self.cur_id.load(std::sync::atomic::Ordering::Relaxed) + 1;
+            self.roll_locked(&mut writer, new_cur_id)?;
             new_cur_id
         } else {
             self.cur_id.load(std::sync::atomic::Ordering::Relaxed)
         };
 
         let file_id = cur_id;
-        let ksz = k.len() as u32;
-        let val_pos = writer.last_val_offset + 16 + ksz as u64; // 16 bytes header size
-        let vsz = v.len() as u32;
-        writer.last_val_offset += 16 + ksz as u64 + vsz as u64; // 16 bytes header size
-        let tstamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u32;
-        let crc = calc_crc(tstamp, ksz, vsz, k, v);
+        let tstamp = now_millis();
+
+        let mut buf = Vec::with_capacity(FILE_HEADER_SZ as usize + total_sz as usize);
+        // a new file starts with its header
+        if writer.cur_file_size == 0 {
+            buf.extend_from_slice(&file_header());
+        }
+
+        let mut entries = Vec::with_capacity(records.len());
+        for r in records {
+            let val_pos =
+                writer.last_val_offset + buf.len() as u64 + (header_sz + r.key.len()) as u64;
+
+            encode_record(r.rtype, tstamp, r.expiry, &r.key, r.val, &mut buf);
+            entries.push(KeyDirEntry::new(
+                file_id,
+                r.val.len() as u32,
+                val_pos,
+                tstamp,
+                r.expiry,
+            ));
+        }
+
+        writer.append(&buf)?;
 
-        let entry = to_db_entry(crc, tstamp, k, v);
+        // then write to im. this happens under the writer lock so that the keydir
+        // always reflects the latest record of a key in the log
+        for (r, entry) in records.iter().zip(entries) {
+            let rec_sz = record_sz(FormatVersion::CURRENT, r.key.len(), r.val.len() as u32);
+            let superseded = match r.rtype {
+                RecordType::Put => self.key_dir.put(r.key.clone(), entry)?,
+                RecordType::Delete => self.key_dir.del(&r.key)?,
+                RecordType::BatchBegin | RecordType::BatchCommit => None,
+            };
+
+            // only a put is live. tombstones & batch markers are dead from the start
+            self.fragmentation
+                .add(file_id, rec_sz, r.rtype == RecordType::Put);
+            if let Some(old) = superseded {
+                self.fragmentation.supersede(r.key.len(), &old);
+            }
+        }
 
-        let _ = writer.writer.as_mut().unwrap().write_all(&entry);
-        let _ = writer.writer.as_mut().unwrap().flush();
+        match self.durability {
+            Durability::Always => writer.sync()?,
+            Durability::GroupCommit => {
+                let seq = writer.seq;
+                drop(writer);
+
+                self.group_commit.wait(seq, || {
+                    let (seq, handle) = {
+                        let writer = self.writer.lock().unwrap();
+                        (writer.seq, writer.sync_handle.clone().unwrap())
+                    };
+                    handle.sync_data()?;
+                    Ok(seq)
+                })?;
+            }
+            Durability::Interval(_) | Durability::None => {}
+        }
 
-        writer.cur_file_size += 16u64 + k.len() as u64 + v.len() as u64;
+        Ok(())
+    }
 
-        Ok(KeyDirEntry::new(file_id, vsz, val_pos, tstamp))
+    /// checks if the given key `k` is present & hasn't expired
+    pub fn has_key(&self, k: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self
+            .key_dir
+            .get(k)?
+            .is_some_and(|entry| !entry.is_expired(now_millis())))
     }
 
     /// deletes the given key
     pub fn del(&self, k: impl AsRef<[u8]>) -> Result<bool> {
-        // TODO if file almost full, then create new file, bump id
         let k = k.as_ref();
-        let k_exists = self.key_dir.has_key(k);
+        let writer = self.writer.lock().unwrap();
+        let k_exists = self.has_key(k)?;
         if k_exists {
             // mark entry as deleted
-            let _ = self.put_with_file_size_check(k, b"TOMBSTONE")?;
-
-            // then del from im
-            self.key_dir.del(k);
+            self.append_locked(writer, &[Record::del(Bytes::copy_from_slice(k))])?;
         }
 
         Ok(k_exists)
     }
 
-    /// merges old files into a single file & generates a hint file
+    /// atomically applies all the puts & deletes in `batch`.
+    ///
+    /// the records are written contiguously between a begin & a commit marker with a
+    /// single write, so a crash leaves either all of them or none of them behind
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut records = Vec::with_capacity(batch.len() + 2);
+        records.push(Record::marker(RecordType::BatchBegin));
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put(k, v) => records.push(Record::put(k.clone(), v, 0)),
+                BatchOp::Del(k) => records.push(Record::del(k.clone())),
+            }
+        }
+        records.push(Record::marker(RecordType::BatchCommit));
+
+        self.append_records(&records)
+    }
+
+    /// merges old files into new ones that respect the file size limit, each with its
+    /// own hint file. the merged files reuse the ids of the newest old files & take
+    /// fresh ones past the active file once those run out.
+    ///
+    /// the merged data & hint files are written under temp names & synced, then a
+    /// journal records the merge before the outputs are renamed into place & the
+    /// inputs deleted. `HydraDB::new` finishes a merge that crashed after writing
+    /// its journal and discards one that crashed before
     pub fn merge(&self) -> Result<()> {
+        self.merge_selected(|files| files).map(|_| ())
+    }
+
+    /// merges only the most fragmented immutable files: up to `max_files` of the ones
+    /// with dead records making up at least `min_dead_ratio` of them, most fragmented
+    /// first. the other files & their hints are left alone. returns the merged files
+    pub fn merge_fragmented(&self, max_files: usize, min_dead_ratio: f64) -> Result<Vec<usize>> {
+        self.merge_selected(|files| {
+            let stats = self.fragmentation.stats();
+            let mut candidates: Vec<(usize, f64)> = files
+                .into_iter()
+                .filter_map(|file_id| {
+                    let stats = stats.get(&file_id)?;
+                    (stats.dead_bytes > 0 && stats.dead_ratio() >= min_dead_ratio)
+                        .then(|| (file_id, stats.dead_ratio()))
+                })
+                .collect();
+            candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+            let mut selected: Vec<usize> = candidates
+                .into_iter()
+                .take(max_files)
+                .map(|(file_id, _)| file_id)
+                .collect();
+            selected.sort();
+            selected
+        })
+    }
+
+    /// merges the immutable files that `select` picks out of all of them (in increasing
+    /// id order) into new ones. returns the merged files
+    fn merge_selected(&self, select: impl FnOnce(Vec<usize>) -> Vec<usize>) -> Result<Vec<usize>> {
         // note: merging may run concurrently with a write operation
         //
         // the goal of merge is to create a hint file.
@@ -309,101 +999,197 @@ impl HydraDB {
         // after the hint file is created, all old files should be deleted.
         //
 
+        // only one merge at a time may own the journal & the temp files
+        let _merging = self.merge_lock.lock().unwrap();
+
         // no merging if no old files
         let cur_id = self.cur_id.load(std::sync::atomic::Ordering::Acquire);
         if cur_id == 0 {
-            return Ok(());
+            return Ok(vec![]);
         }
 
-        // get all the files in the current cask
-        let mut files: Vec<usize> = fs::read_dir(format!("./{}", &self.cur_cask))?
-            .filter_map(|entry| entry.ok())
-            .filter_map(|entry| {
-                let path = entry.path();
-
-                if !path.is_file() {
-                    return None;
-                }
-
-                path.file_name()
-                    .and_then(|name| name.to_str())
-                    .and_then(|name| name.parse::<usize>().ok())
-            })
-            .filter(|file_id| {
-                // we do this check because a concurrent write operation may
-                // create a new file while merging is in progress. so we select
-                // all files that are less than the cur_id that was fixed at the
-                // beginning of the merge
-                *file_id < cur_id
-            })
+        // get all the files in the current cask, in increasing order starting with
+        // the file with the lowest number (an already merged file may also exist).
+        //
+        // a concurrent write operation may create a new file while merging is in
+        // progress. so we select all files that are less than the cur_id that was
+        // fixed at the beginning of the merge
+        let all_files: Vec<usize> = data_file_ids(format!("./{}", &self.cur_cask))?
+            .into_iter()
+            .filter(|file_id| *file_id < cur_id)
             .collect();
+        let files = select(all_files.clone());
+        if files.is_empty() {
+            return Ok(vec![]);
+        }
 
-        // sort them in increasing order starting with file lowest number
-        // (an already merged file may also exist)
-        files.sort();
+        // a file that isn't merged may hold an older put of a key that an input deletes.
+        // the tombstones of inputs newer than such a file have to be carried over
+        let oldest_untouched = all_files.iter().find(|id| !files.contains(id)).copied();
+        let mut tombstones = HashSet::new();
+
+        // the merged files take the places of the inputs. they must come after any file
+        // left in between the inputs so that its older records don't shadow theirs
+        let newest_untouched = all_files
+            .iter()
+            .rev()
+            .find(|id| !files.contains(id) && *id < files.last().unwrap())
+            .copied();
+        let output_ids = files
+            .iter()
+            .copied()
+            .filter(|id| newest_untouched.is_none_or(|untouched| *id > untouched))
+            .collect();
+        let mut bytes_total = 0;
+        for file_id in &files {
+            bytes_total += fs::metadata(format!("./{}/{}", self.cur_cask, file_id))?.len();
+        }
+        let _run = self.merge_progress.start(files.len(), bytes_total);
 
-        // open a temp file for storing merged data
-        let mut temp_file = BufWriter::new(
-            File::options()
-                .create(true)
-                .append(true)
-                .open(format!("./{}/temp", self.cur_cask))?,
-        );
-        let mut temp_file_has_data = false;
-
-        // open hint file for storing hint data
-        let mut hint_file = BufWriter::new(
-            File::options()
-                .create(true)
-                .append(true)
-                .open(format!("./{}/hint", self.cur_cask))?,
+        let fresh_id = || self.reserve_file_id();
+        let mut output = MergeOutput::new(
+            &self.cur_cask,
+            self.max_file_size_threshold,
+            output_ids,
+            &fresh_id,
+            &self.merge_limiter,
+            &self.merge_progress,
         );
 
-        let mut cur_val_offset = 0;
         let mut file_entry = DataFileEntry::new();
+        let now = now_millis();
+
+        // keydir entries of the copied records. they can only be pointed at the merged
+        // files once they're in place
+        let mut merged_entries = vec![];
+        // stats of the merged files. the tombstones are needed as long as the files they
+        // shadow are around, so they count as live
+        let mut stats: BTreeMap<usize, FileStats> = BTreeMap::new();
 
         // merge all files except the last one (active file)
         for file_id in &files {
             let mut file_iter =
-                OptimizedDataFileIterator::new(format!("./{}/{}", self.cur_cask, file_id))?;
+                OptimizedDataFileIterator::new(format!("./{}/{}", self.cur_cask, file_id))?
+                    .with_rate_limiter(self.merge_limiter.clone());
 
-            while file_iter.next_into(&mut file_entry).is_some() {
-                if let Some(entry) = self.key_dir.get(&file_entry.key) {
+            let version = file_iter.version();
+            self.merge_progress.read(version.data_start());
+
+            let keep_tombstones = oldest_untouched.is_some_and(|id| id < *file_id);
+
+            while let Some(res) = file_iter.next_into(&mut file_entry) {
+                res?;
+                self.merge_progress.read(
+                    (version.header_sz() + file_entry.key.len() + file_entry.val.len()) as u64,
+                );
+
+                // a file past the active one is started before the keydir is checked. a
+                // write that the check misses then lands in a later file than the copy
+                if matches!(file_entry.rtype, RecordType::Put | RecordType::Delete) {
+                    output.reserve(record_sz(
+                        FormatVersion::CURRENT,
+                        file_entry.key.len(),
+                        file_entry.val.len() as u32,
+                    ))?;
+                }
+
+                if file_entry.rtype == RecordType::Delete
+                    && keep_tombstones
+                    && !self.key_dir.has_key(&file_entry.key)?
+                    && tombstones.insert(file_entry.key.clone())
+                {
+                    let (output_id, len) = output.tombstone(file_entry.tstamp, &file_entry.key)?;
+                    stats.entry(output_id).or_default().live_bytes += len;
+                    continue;
+                }
+
+                // only puts can be live. other deletes & batch markers are dropped
+                if file_entry.rtype != RecordType::Put {
+                    continue;
+                }
+
+                if let Some(entry) = self.key_dir.get(&file_entry.key)? {
                     // key present in keydir
 
                     // check if the current old file has the valid record verified by presence of
                     // entry in the keydir
                     if entry.file_id == *file_id && entry.val_pos == file_entry.val_pos {
+                        // an expired record is dropped instead of being carried over. it
+                        // becomes a tombstone if it may hide an older put
+                        if entry.is_expired(now) {
+                            if self.key_dir.del_expired(&file_entry.key, now)?
+                                && keep_tombstones
+                                && tombstones.insert(file_entry.key.clone())
+                            {
+                                let (output_id, len) =
+                                    output.tombstone(file_entry.tstamp, &file_entry.key)?;
+                                stats.entry(output_id).or_default().live_bytes += len;
+                            }
+                            continue;
+                        }
+
                         // if yes, then the entry is latest and can be recorded in the hint file
                         // and the merged file
-                        let entry = to_db_entry(
-                            file_entry.crc,
+                        if !file_entry.crc_matches(version) {
+                            return Err(HydraError::Corruption {
+                                file_id: *file_id,
+                                offset: file_entry.val_pos
+                                    - (version.header_sz() + file_entry.key.len()) as u64,
+                            }
+                            .into());
+                        }
+
+                        let new_val = match self
+                            .compaction_filter
+                            .filter(&file_entry.key, &file_entry.val)
+                        {
+                            FilterDecision::Keep => None,
+                            FilterDecision::ChangeValue(val) => Some(val),
+                            FilterDecision::Remove => {
+                                // the key goes away along with the merged files' publication.
+                                // like an expired record, it may have to hide an older put
+                                if keep_tombstones && tombstones.insert(file_entry.key.clone()) {
+                                    let (output_id, len) =
+                                        output.tombstone(file_entry.tstamp, &file_entry.key)?;
+                                    stats.entry(output_id).or_default().live_bytes += len;
+                                }
+                                merged_entries.push((file_entry.key.clone(), entry, None));
+                                continue;
+                            }
+                        };
+                        let val = new_val.as_deref().unwrap_or(&file_entry.val);
+                        // a longer value may need a file past the active one after all
+                        if new_val.is_some()
+                            && output.reserve(record_sz(
+                                FormatVersion::CURRENT,
+                                file_entry.key.len(),
+                                val.len() as u32,
+                            ))?
+                            && !self.key_dir.get(&file_entry.key)?.is_some_and(|e| {
+                                e.file_id == entry.file_id && e.val_pos == entry.val_pos
+                            })
+                        {
+                            continue;
+                        }
+
+                        let (output_id, val_pos) = output.put(
                             file_entry.tstamp,
+                            file_entry.expiry,
                             &file_entry.key,
-                            &file_entry.val,
-                        );
-                        let _ = temp_file.write_all(&entry);
-                        temp_file_has_data = true;
-
-                        let val_pos = cur_val_offset + 16 + file_entry.key.len() as u64;
-                        cur_val_offset += entry.len() as u64;
-                        let entry = to_hint_entry(
-                            file_entry.tstamp,
-                            &file_entry.key,
-                            &file_entry.val,
-                            val_pos,
-                        );
-                        let _ = hint_file.write_all(&entry);
+                            val,
+                        )?;
 
-                        self.key_dir.put(
+                        merged_entries.push((
                             file_entry.key.clone(),
-                            KeyDirEntry {
-                                file_id: cur_id - 1,
-                                val_sz: file_entry.val.len() as u32,
+                            entry,
+                            Some(KeyDirEntry {
+                                file_id: output_id,
+                                val_sz: val.len() as u32,
                                 val_pos,
                                 tstamp: file_entry.tstamp,
-                            },
-                        );
+                                expiry: file_entry.expiry,
+                            }),
+                        ));
                     } else {
                         // key could be present in the active file or a newer old file getting
                         // processed in future iterations of this loop.
@@ -413,43 +1199,216 @@ impl HydraDB {
                     // key deleted so skip processing it
                 }
             }
+
+            let progress = self.merge_progress.file_done();
+            info!(
+                "./{}: merged {file_id} ({}/{} files, {:.0}%)",
+                self.cur_cask,
+                progress.files_done,
+                progress.files_total,
+                progress.fraction_done() * 100.0
+            );
         }
 
-        for file_id in &files {
-            debug!("rming ./{}/{}", self.cur_cask, file_id);
+        // a snapshot of the keydir would point at the records being moved. it has to go
+        // before the journal commits to moving them
+        KeyDirSnapshot::remove(&self.cur_cask)?;
+
+        // the outputs must be durable before the journal makes them authoritative
+        let journal = MergeJournal {
+            inputs: files.clone(),
+            outputs: output.finish()?,
+        };
+        journal.write(&self.cur_cask)?;
+
+        debug!("publishing merge of {:?} as {:?}", files, journal.outputs);
+        {
+            // writers wait while the keydir & the file stats are switched over to the
+            // merged file so that a record they supersede is accounted for in the
+            // right file. taken before the publish guard so that a writer reading under
+            // the lock, like `compare_and_swap`, never waits for the epoch to turn even
+            let _writer = self.writer.lock().unwrap();
+            let _publishing = PublishGuard::new(&self.merge_epoch);
+            journal.publish(&self.cur_cask)?;
+
+            // the cache may still hold handles to the inputs that merged files replaced
+            for file_id in &journal.outputs {
+                self.file_cache.invalidate(*file_id);
+            }
+
+            // a key written or deleted since its record was copied must keep its newer
+            // entry. the stale copy in the merged file is shadowed by the newer record
+            // on restore as that lives in a later file
+            for (key, copied, merged) in merged_entries {
+                // a record dropped by the compaction filter
+                let Some(merged) = merged else {
+                    self.key_dir
+                        .compare_and_del(key, copied.file_id, copied.val_pos)?;
+                    continue;
+                };
+
+                let rec_sz = record_sz(FormatVersion::CURRENT, key.len(), merged.val_sz);
+                let stats = stats.entry(merged.file_id).or_default();
+                if self
+                    .key_dir
+                    .compare_and_set(key, copied.file_id, copied.val_pos, merged)?
+                {
+                    stats.live_bytes += rec_sz;
+                } else {
+                    stats.dead_bytes += rec_sz;
+                }
+            }
 
-            fs::remove_file(format!("./{}/{}", self.cur_cask, file_id))?;
+            for file_id in &files {
+                self.fragmentation.remove(*file_id);
+            }
+            for (file_id, stats) in stats {
+                self.fragmentation
+                    .track(file_id, FormatVersion::CURRENT, stats);
+            }
         }
 
-        debug!("cur id {}", cur_id);
+        journal.remove_inputs(&self.cur_cask)?;
+        for file_id in &files {
+            self.file_cache.invalidate(*file_id);
+        }
+        MergeJournal::remove(&self.cur_cask)?;
 
-        if temp_file_has_data {
-            let _res = fs::rename(
-                format!("{}/temp", self.cur_cask),
-                format!("{}/{}", self.cur_cask, cur_id - 1),
-            );
-        } else {
-            fs::remove_file(format!("{}/temp", self.cur_cask))?;
-            fs::remove_file(format!("{}/hint", self.cur_cask))?;
+        Ok(files)
+    }
+
+    /// returns the hit, miss & eviction counts of the file cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.file_cache.stats()
+    }
+
+    /// lists all the keys in the store that haven't expired. see `keys` to walk them
+    /// without holding them all
+    pub fn list_all(&self) -> Result<Vec<Bytes>> {
+        self.keys().collect()
+    }
+
+    /// iterates lazily over the keys that haven't expired. `Keys::cursor` tells where
+    /// to resume from with `Keys::after`, to page through them
+    pub fn keys(&self) -> Keys<'_> {
+        Keys::new(self)
+    }
+
+    /// iterates lazily over the keys that haven't expired & their values. `Iter::cursor`
+    /// tells where to resume from with `Iter::after`, to page through them
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self)
+    }
+
+    /// iterates over the keys within `range` that haven't expired & their values, in
+    /// key order. needs an ordered keydir (`KeyDirKind::Ordered`)
+    pub fn scan<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Scan<'_> {
+        let bound = |bound: Bound<&K>| bound.map(|k| Bytes::copy_from_slice(k.as_ref()));
+        Scan::new(self, bound(range.start_bound()), bound(range.end_bound()))
+    }
+
+    /// iterates over the keys starting with `prefix` that haven't expired & their
+    /// values, in key order. needs an ordered keydir (`KeyDirKind::Ordered`)
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        Scan::prefix(self, prefix.as_ref())
+    }
+
+    pub(crate) fn key_dir(&self) -> &KeyDir {
+        &self.key_dir
+    }
+}
+
+/// reads & verifies the value of `k` that `entry` points at in `data_file`
+pub(crate) fn read_record(data_file: &DataFile, k: &[u8], entry: &KeyDirEntry) -> Result<Bytes> {
+    // read the whole record so that it can be verified against its header
+    let span = record_span(data_file.version, k, entry)?;
+    let rec = read_span(data_file, entry.file_id, span)?;
+    check_record(data_file.version, k, entry, rec)
+}
+
+/// where the record that `entry` points at lies in its data file. fails if the record
+/// can't start where the entry says, like when the file under its id was replaced by
+/// one of another format
+fn record_span(version: FormatVersion, k: &[u8], entry: &KeyDirEntry) -> Result<Range<u64>> {
+    let offset = entry
+        .val_pos
+        .checked_sub((version.header_sz() + k.len()) as u64)
+        .ok_or(HydraError::Corruption {
+            file_id: entry.file_id,
+            offset: entry.val_pos,
+        })?;
+    Ok(offset..entry.val_pos + entry.val_sz as u64)
+}
+
+/// reads `span` of the data file `file_id`
+fn read_span(data_file: &DataFile, file_id: usize, span: Range<u64>) -> Result<Bytes> {
+    let mut buf = vec![0; (span.end - span.start) as usize];
+    match data_file.file.read_exact_at(&mut buf, span.start) {
+        Ok(()) => Ok(buf.into()),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(HydraError::Corruption {
+            file_id,
+            offset: span.start,
         }
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
 
-        Ok(())
+/// verifies the record `rec` of `k` that `entry` points at & returns its value
+fn check_record(
+    version: FormatVersion,
+    k: &[u8],
+    entry: &KeyDirEntry,
+    rec: Bytes,
+) -> Result<Bytes> {
+    let header_sz = version.header_sz();
+    let offset = record_span(version, k, entry)?.start;
+    let corruption = || HydraError::Corruption {
+        file_id: entry.file_id,
+        offset,
+    };
+
+    let header = RecordHeader::decode(version, &rec[..header_sz]).map_err(|_| corruption())?;
+    if header.rtype != RecordType::Put
+        || header.ksz as usize != k.len()
+        || header.vsz != entry.val_sz
+    {
+        return Err(corruption().into());
     }
 
-    /// lists all the keys in the store
-    pub fn list_all(&self) -> Option<Vec<Bytes>> {
-        self.key_dir.keys()
+    let (key, val) = rec[header_sz..].split_at(k.len());
+    if key != k || header.calc_crc(version, key, val) != header.crc {
+        return Err(corruption().into());
     }
+
+    Ok(rec.slice(header_sz + k.len()..))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
-
-    use crate::hydradb::HydraDBBuilder;
+    use bytes::Bytes;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::compaction::{FileStats, FilterDecision};
+    use crate::durability::Durability;
+    use crate::error::HydraError;
+    use crate::format::{
+        FILE_HEADER_SZ, FormatVersion, MAGIC, RecordHeader, RecordType, read_version,
+    };
+    use crate::hydradb::{HydraDB, HydraDBBuilder, record_span};
+    use crate::key_dir::{KeyDirEntry, KeyDirKind};
+    use crate::merge_journal::MergeJournal;
+    use crate::merge_progress::MergeProgress;
+    use crate::utils::data_file_ids;
+    use crate::write_batch::WriteBatch;
     use env_logger;
-    use log::debug;
-    use rand::Rng;
 
     #[test]
     fn test_del() {
@@ -472,7 +1431,7 @@ mod tests {
         let _ = env_logger::builder()
             .is_test(true) // Ensures output is captured by cargo test
             .try_init();
-        let mut db = HydraDBBuilder::new()
+        let db = HydraDBBuilder::new()
             .with_cask("names-to-addresses")
             .build()
             .unwrap();
@@ -484,9 +1443,10 @@ mod tests {
         db.put("jane", "mk").unwrap();
 
         assert_eq!(db.key_dir.len(), 6);
-        let e = db.key_dir.get("pooja").unwrap();
+        let e = db.key_dir.get("pooja").unwrap().unwrap();
         assert_eq!(e.file_id, 0);
-        assert_eq!(e.val_pos, 21);
+        // file header + record header + key
+        assert_eq!(e.val_pos, 5 + 29 + 5);
 
         let val = db.get("pooja");
         assert!(val.is_ok());
@@ -514,16 +1474,16 @@ mod tests {
             .build()
             .unwrap();
         assert_eq!(db.key_dir.len(), 6);
-        let e = db.key_dir.get("pooja").unwrap();
+        let e = db.key_dir.get("pooja").unwrap().unwrap();
         assert_eq!(e.file_id, 0);
         assert_eq!(e.val_pos, 21);
-        let e = db.key_dir.get("abhi").unwrap();
+        let e = db.key_dir.get("abhi").unwrap().unwrap();
         assert_eq!(e.file_id, 0);
         assert_eq!(e.val_pos, 53);
-        let e = db.key_dir.get("pads").unwrap();
+        let e = db.key_dir.get("pads").unwrap().unwrap();
         assert_eq!(e.file_id, 0);
         assert_eq!(e.val_pos, 78);
-        let e = db.key_dir.get("jane").unwrap();
+        let e = db.key_dir.get("jane").unwrap().unwrap();
         assert_eq!(e.file_id, 0);
         assert_eq!(e.val_pos, 155);
 
@@ -546,9 +1506,7 @@ mod tests {
         db.put("ashu", "baner").unwrap();
         db.put("swap", "usa").unwrap();
         db.put("jane", "mk").unwrap();
-        let keys = db.list_all();
-        assert!(keys.is_some());
-        let keys = keys.unwrap();
+        let keys = db.list_all().unwrap();
         assert_eq!(keys.len(), 6);
 
         let _ = fs::remove_dir_all("./names-to-addresses");
@@ -568,7 +1526,7 @@ mod tests {
     fn test_split_file() {
         let db = HydraDBBuilder::new()
             .with_cask("split_test")
-            .with_file_limit(60)
+            .with_file_limit(100)
             .build()
             .unwrap();
         db.put("abhi", "rust").unwrap();
@@ -593,7 +1551,7 @@ mod tests {
 
         let db = HydraDBBuilder::new()
             .with_cask("merge_test")
-            .with_file_limit(60)
+            .with_file_limit(100)
             .build()
             .unwrap();
         db.put("abhi", "rust").unwrap();
@@ -645,8 +1603,17 @@ mod tests {
         let result = db.merge();
         assert!(result.is_ok());
 
+        // the 13 live records of the old files get split two to a file, each with a hint
         let files: Vec<_> = fs::read_dir("./merge_test").unwrap().collect();
-        assert_eq!(files.len(), 3);
+        assert_eq!(files.len(), 7 * 2 + 1);
+        for id in data_file_ids("./merge_test").unwrap() {
+            assert!(fs::metadata(format!("./merge_test/{id}")).unwrap().len() <= 100);
+            assert_eq!(
+                fs::exists(format!("./merge_test/{id}.hint")).unwrap(),
+                id != 9
+            );
+        }
+        assert_eq!(data_file_ids("./merge_test").unwrap()[0], 2);
 
         let val = db.get("pooj");
         assert!(val.is_ok());
@@ -657,16 +1624,286 @@ mod tests {
         assert!(val.is_ok());
         let val = val.unwrap();
         assert_eq!(val, Some("jula".into()));
+        drop(db);
+
+        // the merged files are restored from their hints
+        let db = HydraDBBuilder::new()
+            .with_cask("merge_test")
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 15);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("ashu").unwrap(), Some("scal".into()));
+        assert_eq!(db.get("pooj").unwrap(), Some("dops".into()));
 
         let _ = fs::remove_dir_all("./merge_test");
     }
 
+    #[test]
+    fn test_get_detects_corruption() {
+        let db = HydraDBBuilder::new()
+            .with_cask("corruption_test")
+            .build()
+            .unwrap();
+        db.put("abhi", "rust").unwrap();
+        db.put("pads", "java").unwrap();
+
+        // flip a bit in the value of the first record
+        let e = db.key_dir.get("abhi").unwrap().unwrap();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open("./corruption_test/0")
+            .unwrap();
+        let mut b = [0u8; 1];
+        file.read_exact_at(&mut b, e.val_pos).unwrap();
+        file.write_all_at(&[b[0] ^ 1], e.val_pos).unwrap();
+
+        let err = db.get("abhi").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HydraError>(),
+            Some(&HydraError::Corruption {
+                file_id: 0,
+                offset: FILE_HEADER_SZ
+            })
+        );
+
+        // other records are unaffected
+        assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+
+        let _ = fs::remove_dir_all("./corruption_test");
+    }
+
+    #[test]
+    fn test_torn_tail_truncated_on_restore() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("torn_tail_test")
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+        }
+
+        // simulate a crash halfway through writing a record
+        let mut file = File::options()
+            .append(true)
+            .open("./torn_tail_test/0")
+            .unwrap();
+        file.write_all(&[0, 0, 0, 1, 0, 0]).unwrap();
+        drop(file);
+
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("torn_tail_test")
+                .build()
+                .unwrap();
+            assert_eq!(
+                fs::metadata("./torn_tail_test/0").unwrap().len(),
+                5 + 2 * 37
+            );
+            assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+
+            db.put("swap", ".net").unwrap();
+            assert_eq!(db.get("swap").unwrap(), Some(".net".into()));
+        }
+
+        let db = HydraDBBuilder::new()
+            .with_cask("torn_tail_test")
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 3);
+        assert_eq!(db.get("swap").unwrap(), Some(".net".into()));
+
+        let _ = fs::remove_dir_all("./torn_tail_test");
+    }
+
+    #[test]
+    fn test_corrupt_immutable_file_fails_restore() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("corrupt_immutable_test")
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+            db.put("swap", ".net").unwrap();
+            assert_eq!(db.get_active_file(), 1);
+        }
+
+        // flip a bit in the value of the first record of 0. the one after it is intact
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open("./corrupt_immutable_test/0")
+            .unwrap();
+        let mut b = [0u8; 1];
+        file.read_exact_at(&mut b, 5 + 29 + 4).unwrap();
+        file.write_all_at(&[b[0] ^ 1], 5 + 29 + 4).unwrap();
+        let len = file.metadata().unwrap().len();
+        drop(file);
+
+        // dropping the rest of 0 would bring back older values of its keys
+        let err = HydraDBBuilder::new()
+            .with_cask("corrupt_immutable_test")
+            .with_file_limit(100)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HydraError>(),
+            Some(&HydraError::Corruption {
+                file_id: 0,
+                offset: 5
+            })
+        );
+        assert_eq!(
+            fs::metadata("./corrupt_immutable_test/0").unwrap().len(),
+            len
+        );
+
+        let _ = fs::remove_dir_all("./corrupt_immutable_test");
+    }
+
+    #[test]
+    fn test_restore_all_files() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("restore_all_files_test")
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+            db.put("swap", ".net").unwrap();
+            db.put("pooj", "pyth").unwrap();
+            db.put("abhi", "zigg").unwrap();
+            db.del("pads").unwrap();
+            assert_eq!(db.get_active_file(), 2);
+        }
+
+        let db = HydraDBBuilder::new()
+            .with_cask("restore_all_files_test")
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 3);
+        assert_eq!(db.get("abhi").unwrap(), Some("zigg".into()));
+        assert_eq!(db.get("swap").unwrap(), Some(".net".into()));
+        assert_eq!(db.get("pooj").unwrap(), Some("pyth".into()));
+        assert_eq!(db.get("pads").unwrap(), None);
+
+        let _ = fs::remove_dir_all("./restore_all_files_test");
+    }
+
+    #[test]
+    fn test_durability_modes() {
+        for (cask, durability) in [
+            ("durability_always_test", Durability::Always),
+            ("durability_group_test", Durability::GroupCommit),
+            ("durability_interval_test", Durability::Interval(5)),
+        ] {
+            {
+                let db = HydraDBBuilder::new()
+                    .with_cask(cask)
+                    .with_file_limit(200)
+                    .with_durability(durability)
+                    .build()
+                    .unwrap();
+
+                std::thread::scope(|s| {
+                    for t in 0..4 {
+                        let db = &db;
+                        s.spawn(move || {
+                            for i in 0..25 {
+                                db.put(format!("key-{t}-{i}"), format!("val-{i}")).unwrap();
+                            }
+                        });
+                    }
+                });
+            }
+
+            let db = HydraDBBuilder::new().with_cask(cask).build().unwrap();
+            assert_eq!(db.key_dir.len(), 100);
+            assert_eq!(db.get("key-3-24").unwrap(), Some("val-24".into()));
+
+            let _ = fs::remove_dir_all(format!("./{cask}"));
+        }
+    }
+
+    #[test]
+    fn test_write_batch() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("write_batch_test")
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put("swap", ".net").put("abhi", "zigg").del("pads");
+            db.write(&batch).unwrap();
+
+            assert_eq!(db.key_dir.len(), 2);
+            assert_eq!(db.get("abhi").unwrap(), Some("zigg".into()));
+            assert_eq!(db.get("pads").unwrap(), None);
+        }
+
+        let db = HydraDBBuilder::new()
+            .with_cask("write_batch_test")
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 2);
+        assert_eq!(db.get("abhi").unwrap(), Some("zigg".into()));
+        assert_eq!(db.get("swap").unwrap(), Some(".net".into()));
+        assert_eq!(db.get("pads").unwrap(), None);
+
+        let _ = fs::remove_dir_all("./write_batch_test");
+    }
+
+    #[test]
+    fn test_uncommitted_batch_discarded_on_restore() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("uncommitted_batch_test")
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put("swap", ".net").del("abhi");
+            db.write(&batch).unwrap();
+        }
+
+        // simulate a crash before the commit marker made it to disk
+        let path = "./uncommitted_batch_test/0";
+        let len = fs::metadata(path).unwrap().len();
+        let file = File::options().write(true).open(path).unwrap();
+        let marker_sz = FormatVersion::CURRENT.header_sz() as u64;
+        file.set_len(len - marker_sz).unwrap();
+        drop(file);
+
+        let db = HydraDBBuilder::new()
+            .with_cask("uncommitted_batch_test")
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 1);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("swap").unwrap(), None);
+        // the partial batch is cut off the active file
+        assert_eq!(fs::metadata(path).unwrap().len(), 5 + 29 + 8);
+
+        let _ = fs::remove_dir_all("./uncommitted_batch_test");
+    }
+
     #[test]
     fn test_hint_file_restore() {
         {
             let db = HydraDBBuilder::new()
                 .with_cask("hint_file_restore_test")
-                .with_file_limit(60)
+                .with_file_limit(100)
                 .build()
                 .unwrap();
             db.put("abhi", "rust").unwrap();
@@ -688,12 +1925,1293 @@ mod tests {
         // the keydir should be now gone
 
         // restore from hint file
-        let _ = HydraDBBuilder::new()
+        let db = HydraDBBuilder::new()
             .with_cask("hint_file_restore_test")
-            .with_file_limit(60)
+            .with_file_limit(100)
             .build()
             .unwrap();
+        assert!(fs::exists("./hint_file_restore_test/0.hint").unwrap());
+        assert_eq!(db.key_dir.len(), 4);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("pooj").unwrap(), Some("pyth".into()));
 
         let _ = fs::remove_dir_all("./hint_file_restore_test");
     }
+
+    #[test]
+    fn test_truncated_hint_falls_back_to_scan() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("truncated_hint_test")
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+            db.put("swap", ".net").unwrap();
+            db.merge().unwrap();
+        }
+
+        // cut the last hint entry short
+        let hint = File::options()
+            .write(true)
+            .open("./truncated_hint_test/0.hint")
+            .unwrap();
+        let len = hint.metadata().unwrap().len();
+        hint.set_len(len - 3).unwrap();
+        drop(hint);
+
+        let db = HydraDBBuilder::new()
+            .with_cask("truncated_hint_test")
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 3);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+
+        let _ = fs::remove_dir_all("./truncated_hint_test");
+    }
+
+    #[test]
+    fn test_tombstone_value_survives_restore() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("tombstone_value_test")
+                .build()
+                .unwrap();
+            db.put("abhi", "TOMBSTONE").unwrap();
+            db.put("pads", "java").unwrap();
+            db.del("pads").unwrap();
+        }
+
+        let db = HydraDBBuilder::new()
+            .with_cask("tombstone_value_test")
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 1);
+        assert_eq!(db.get("abhi").unwrap(), Some("TOMBSTONE".into()));
+        assert_eq!(db.get("pads").unwrap(), None);
+
+        let _ = fs::remove_dir_all("./tombstone_value_test");
+    }
+
+    #[test]
+    fn test_legacy_cask_upgrade() {
+        fs::create_dir_all("./legacy_cask_test").unwrap();
+        fs::copy("./test/0", "./legacy_cask_test/0").unwrap();
+
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("legacy_cask_test")
+                .build()
+                .unwrap();
+            assert_eq!(db.key_dir.len(), 6);
+
+            // new records never get appended to a file in the old format
+            db.put("abhi", "rust").unwrap();
+            assert_eq!(db.get_active_file(), 1);
+            assert_eq!(
+                fs::read("./legacy_cask_test/0").unwrap(),
+                fs::read("./test/0").unwrap()
+            );
+
+            db.merge().unwrap();
+        }
+
+        let db = HydraDBBuilder::new()
+            .with_cask("legacy_cask_test")
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 6);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("pooja").unwrap(), Some("kalyaninagar".into()));
+
+        let _ = fs::remove_dir_all("./legacy_cask_test");
+    }
+
+    #[test]
+    fn test_v1_files_migrated_by_merge() {
+        // a v1 file: header followed by typed records with u32 tstamps
+        let mut data = MAGIC.to_vec();
+        data.push(1);
+        for (rtype, k, v) in [
+            (RecordType::Put, "abhi", "rust"),
+            (RecordType::Put, "pads", "java"),
+            (RecordType::Delete, "pads", ""),
+        ] {
+            let header = RecordHeader {
+                crc: 0,
+                rtype,
+                tstamp: 42,
+                expiry: 0,
+                ksz: k.len() as u32,
+                vsz: v.len() as u32,
+            };
+            let crc = header.calc_crc(FormatVersion::V1, k.as_bytes(), v.as_bytes());
+            data.extend_from_slice(&crc.to_be_bytes());
+            data.push(rtype as u8);
+            data.extend_from_slice(&42u32.to_be_bytes());
+            data.extend_from_slice(&header.ksz.to_be_bytes());
+            data.extend_from_slice(&header.vsz.to_be_bytes());
+            data.extend_from_slice(k.as_bytes());
+            data.extend_from_slice(v.as_bytes());
+        }
+        fs::create_dir_all("./v1_migration_test").unwrap();
+        fs::write("./v1_migration_test/0", &data).unwrap();
+
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("v1_migration_test")
+                .build()
+                .unwrap();
+            assert_eq!(db.key_dir.len(), 1);
+            assert_eq!(db.key_dir.get("abhi").unwrap().unwrap().tstamp, 42);
+            assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+
+            db.put("swap", ".net").unwrap();
+            assert_eq!(db.get_active_file(), 1);
+            db.merge().unwrap();
+        }
+
+        let file = File::open("./v1_migration_test/0").unwrap();
+        assert_eq!(read_version(&file).unwrap(), FormatVersion::CURRENT);
+
+        let db = HydraDBBuilder::new()
+            .with_cask("v1_migration_test")
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 2);
+        assert_eq!(db.key_dir.get("abhi").unwrap().unwrap().tstamp, 42);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("swap").unwrap(), Some(".net".into()));
+
+        let _ = fs::remove_dir_all("./v1_migration_test");
+    }
+
+    #[test]
+    fn test_ttl() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("ttl_test")
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put_with_ttl("abhi", "java", Duration::from_millis(50))
+                .unwrap();
+            db.put_with_ttl("pads", "java", Duration::from_secs(3600))
+                .unwrap();
+            db.put_with_ttl("swap", ".net", Duration::from_millis(50))
+                .unwrap();
+            assert_eq!(db.get("abhi").unwrap(), Some("java".into()));
+
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(db.get("abhi").unwrap(), None);
+            assert!(!db.has_key("swap").unwrap());
+            assert_eq!(db.list_all().unwrap(), vec![Bytes::from("pads")]);
+            assert!(!db.del("abhi").unwrap());
+
+            // expired records don't make it into the merged file
+            db.put("jane", "mk").unwrap();
+            db.merge().unwrap();
+            assert_eq!(db.key_dir.len(), 2);
+            assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+        }
+
+        // an expired record still hides the older value of its key
+        let db = HydraDBBuilder::new()
+            .with_cask("ttl_test")
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        assert_eq!(db.key_dir.len(), 2);
+        assert_eq!(db.get("abhi").unwrap(), None);
+        assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+
+        let _ = fs::remove_dir_all("./ttl_test");
+    }
+
+    #[test]
+    fn test_expired_records_absent_on_restore() {
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask("ttl_restore_test")
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put_with_ttl("abhi", "java", Duration::from_millis(50))
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let db = HydraDBBuilder::new()
+            .with_cask("ttl_restore_test")
+            .build()
+            .unwrap();
+        assert!(db.key_dir.is_empty());
+        assert_eq!(db.get("abhi").unwrap(), None);
+
+        let _ = fs::remove_dir_all("./ttl_restore_test");
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let db = HydraDBBuilder::new()
+            .with_cask("conditional_writes_test")
+            .build()
+            .unwrap();
+
+        assert!(!db.replace_if_exists("abhi", "java").unwrap());
+        assert!(db.put_if_absent("abhi", "rust").unwrap());
+        assert!(!db.put_if_absent("abhi", "java").unwrap());
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert!(db.replace_if_exists("abhi", "java").unwrap());
+        assert_eq!(db.get("abhi").unwrap(), Some("java".into()));
+
+        assert!(!db.compare_and_swap("abhi", Some(b"rust"), "go").unwrap());
+        assert!(!db.compare_and_swap("abhi", None, "go").unwrap());
+        assert!(db.compare_and_swap("abhi", Some(b"java"), "go").unwrap());
+        assert!(db.compare_and_swap("pads", None, "c").unwrap());
+        assert_eq!(db.get("abhi").unwrap(), Some("go".into()));
+        assert_eq!(db.get("pads").unwrap(), Some("c".into()));
+
+        // an expired key counts as absent
+        db.put_with_ttl("swap", ".net", Duration::from_millis(10))
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!db.replace_if_exists("swap", "java").unwrap());
+        assert!(db.put_if_absent("swap", "java").unwrap());
+        assert_eq!(db.get("swap").unwrap(), Some("java".into()));
+
+        let _ = fs::remove_dir_all("./conditional_writes_test");
+    }
+
+    #[test]
+    fn test_concurrent_compare_and_swap() {
+        let db = Arc::new(
+            HydraDBBuilder::new()
+                .with_cask("concurrent_cas_test")
+                .build()
+                .unwrap(),
+        );
+        db.put("counter", "0").unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        loop {
+                            let cur = db.get("counter").unwrap().unwrap();
+                            let n: u32 = std::str::from_utf8(&cur).unwrap().parse().unwrap();
+                            if db
+                                .compare_and_swap("counter", Some(&cur), (n + 1).to_string())
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(db.get("counter").unwrap(), Some("400".into()));
+
+        let _ = fs::remove_dir_all("./concurrent_cas_test");
+    }
+
+    #[test]
+    fn test_file_cache_bounded_across_merge() {
+        let db = HydraDBBuilder::new()
+            .with_cask("file_cache_merge_test")
+            .with_file_limit(100)
+            .with_cache_size(2)
+            .build()
+            .unwrap();
+        let keys = [
+            "abhi", "pads", "swap", "pooj", "jane", "zigg", "ashu", "muma",
+        ];
+        for k in keys {
+            db.put(k, k.to_uppercase()).unwrap();
+        }
+        assert_eq!(db.get_active_file(), 3);
+
+        for k in keys {
+            assert_eq!(db.get(k).unwrap(), Some(k.to_uppercase().into()));
+        }
+        let stats = db.cache_stats();
+        assert_eq!(stats.open_files, 2);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.evictions, 2);
+
+        // caches a handle to file 2, which merge replaces with the merged file
+        assert_eq!(db.get("ashu").unwrap(), Some("ASHU".into()));
+        db.merge().unwrap();
+        for k in keys {
+            assert_eq!(db.get(k).unwrap(), Some(k.to_uppercase().into()));
+        }
+        assert!(db.cache_stats().open_files <= 2);
+
+        let _ = fs::remove_dir_all("./file_cache_merge_test");
+    }
+
+    #[test]
+    fn test_merge_with_concurrent_writes() {
+        let cask = "merge_concurrent_writes_test";
+        let db = Arc::new(
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(512)
+                .build()
+                .unwrap(),
+        );
+        let done = Arc::new(AtomicBool::new(false));
+
+        // each writer owns its keys & returns what it expects them to hold
+        let writers: Vec<_> = (0..4)
+            .map(|w| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let mut expected = HashMap::new();
+                    for op in 0..300 {
+                        let key = format!("w{w}-k{}", op % 8);
+                        if op % 5 == 4 {
+                            db.del(&key).unwrap();
+                            expected.remove(&key);
+                        } else {
+                            let val = format!("{w}-{op}");
+                            db.put(key.clone(), val.clone()).unwrap();
+                            expected.insert(key, val);
+                        }
+                    }
+                    expected
+                })
+            })
+            .collect();
+
+        // readers must never see an error or another writer's value while merges
+        // move records around under them
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let (db, done) = (db.clone(), done.clone());
+                thread::spawn(move || {
+                    while !done.load(Ordering::Acquire) {
+                        for w in 0..4 {
+                            for k in 0..8 {
+                                if let Some(val) = db.get(format!("w{w}-k{k}")).unwrap() {
+                                    assert!(val.starts_with(format!("{w}-").as_bytes()));
+                                }
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let merger = {
+            let (db, done) = (db.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Acquire) {
+                    db.merge().unwrap();
+                }
+            })
+        };
+
+        let mut expected = HashMap::new();
+        for writer in writers {
+            expected.extend(writer.join().unwrap());
+        }
+        done.store(true, Ordering::Release);
+        for handle in readers.into_iter().chain([merger]) {
+            handle.join().unwrap();
+        }
+        db.merge().unwrap();
+
+        let check = |db: &HydraDB| {
+            for w in 0..4 {
+                for k in 0..8 {
+                    let key = format!("w{w}-k{k}");
+                    assert_eq!(
+                        db.get(&key).unwrap(),
+                        expected.get(&key).map(|v| Bytes::from(v.clone())),
+                        "{key}"
+                    );
+                }
+            }
+        };
+        check(&db);
+        drop(db);
+
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(512)
+            .build()
+            .unwrap();
+        check(&db);
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_file_stats() {
+        let cask = "file_stats_test";
+        let stats = {
+            let db = HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+            db.put("abhi", "rusty").unwrap();
+            db.del("pads").unwrap();
+
+            // each record is 29 + 4 bytes plus its value
+            let stats = db.file_stats();
+            assert_eq!(
+                stats[&0],
+                FileStats {
+                    live_bytes: 0,
+                    dead_bytes: 37 + 37
+                }
+            );
+            assert_eq!(
+                stats[&1],
+                FileStats {
+                    live_bytes: 38,
+                    dead_bytes: 33
+                }
+            );
+            stats
+        };
+
+        // the stats of a reopened db are worked out from its files
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        assert_eq!(db.file_stats(), stats);
+
+        // nothing in 0 was live, so merging just drops it
+        db.merge().unwrap();
+        assert_eq!(db.file_stats(), [(1, stats[&1])].into_iter().collect());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let cask = "background_compaction_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(100)
+            .with_compaction_dead_ratio(0.5)
+            .with_compaction_interval(Duration::from_millis(10))
+            .build_shared()
+            .unwrap();
+        assert!(!db.compact_if_due().unwrap());
+
+        for i in 0..6 {
+            db.put("abhi", format!("rust{i}")).unwrap();
+        }
+        db.put("pads", "java").unwrap();
+        assert!(db.get_active_file() >= 3);
+
+        let start = std::time::Instant::now();
+        while fs::exists(format!("./{cask}/0")).unwrap() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(db.get("abhi").unwrap(), Some("rust5".into()));
+        assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+
+        // dropping the db stops the thread. a job that is running holds the db until
+        // it's done, so the db goes away shortly after
+        let weak = Arc::downgrade(&db);
+        drop(db);
+        let start = std::time::Instant::now();
+        while weak.upgrade().is_some() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_selective_merge() {
+        let cask = "selective_merge_test";
+        let build = || {
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(120)
+                .build()
+                .unwrap()
+        };
+        let db = build();
+        // three puts per file
+        for (k, v) in [("a", "1"), ("b", "1"), ("x", "1")] {
+            db.put(k, v).unwrap();
+        }
+        for (k, v) in [("c", "1"), ("d", "1"), ("f", "1")] {
+            db.put(k, v).unwrap();
+        }
+        db.put("a", "2").unwrap();
+        db.put("x", "2").unwrap();
+        db.del("c").unwrap();
+        db.put("a", "3").unwrap();
+        db.put("e", "1").unwrap();
+        assert_eq!(db.get_active_file(), 3);
+        let file_1 = fs::read(format!("./{cask}/1")).unwrap();
+
+        // 1 is the least fragmented file
+        assert_eq!(db.merge_fragmented(2, 0.0).unwrap(), [0, 2]);
+        assert!(!fs::exists(format!("./{cask}/0")).unwrap());
+        assert_eq!(fs::read(format!("./{cask}/1")).unwrap(), file_1);
+        // the tombstone of "c" still hides its put in 1, so 2 can't have a hint
+        assert!(!fs::exists(format!("./{cask}/2.hint")).unwrap());
+        assert_eq!(db.merge_fragmented(2, 0.5).unwrap(), Vec::<usize>::new());
+
+        let check = |db: &HydraDB| {
+            for (k, v) in [("a", "3"), ("b", "1"), ("d", "1"), ("e", "1"), ("f", "1")] {
+                assert_eq!(db.get(k).unwrap(), Some(v.into()), "{k}");
+            }
+            assert_eq!(db.get("x").unwrap(), Some("2".into()));
+            assert_eq!(db.get("c").unwrap(), None);
+            assert_eq!(db.key_dir.len(), 6);
+        };
+        check(&db);
+        drop(db);
+
+        let db = build();
+        check(&db);
+
+        // with 1 merged too, nothing is left for the tombstone to hide
+        db.merge().unwrap();
+        assert!(fs::exists(format!("./{cask}/2.hint")).unwrap());
+        drop(db);
+        check(&build());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_selective_merge_respects_file_limit() {
+        let cask = "selective_merge_limit_test";
+        let build = || {
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(120)
+                .build()
+                .unwrap()
+        };
+        let db = build();
+        // three puts of 31 bytes per file
+        for k in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            db.put(k, "1").unwrap();
+        }
+        db.put("a", "2").unwrap();
+        for k in ["i", "j"] {
+            db.put(k, "1").unwrap();
+        }
+        db.put("g", "2").unwrap();
+        db.put("k", "1").unwrap();
+        assert_eq!(db.get_active_file(), 4);
+
+        // 1 is left in between, so only the id of 2 can be reused. the 4 live records
+        // of 0 & 2 don't fit in one file, so the rest goes past the active file
+        assert_eq!(db.merge_fragmented(2, 0.0).unwrap(), [0, 2]);
+        assert_eq!(db.get_active_file(), 6);
+        for file_id in data_file_ids(format!("./{cask}")).unwrap() {
+            let len = fs::metadata(format!("./{cask}/{file_id}")).unwrap().len();
+            assert!(len <= 120, "{file_id} is {len} bytes");
+        }
+
+        let check = |db: &HydraDB| {
+            assert_eq!(db.get("a").unwrap(), Some("2".into()));
+            assert_eq!(db.get("g").unwrap(), Some("2".into()));
+            for k in ["b", "c", "d", "e", "f", "h", "i", "j", "k"] {
+                assert_eq!(db.get(k).unwrap(), Some("1".into()), "{k}");
+            }
+            assert_eq!(db.key_dir.len(), 11);
+        };
+        check(&db);
+        drop(db);
+        check(&build());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_merge_rate_limit_and_progress() {
+        let cask = "merge_rate_limit_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(100)
+            .with_merge_rate_limit(1 << 20)
+            .build()
+            .unwrap();
+        for (k, v) in [("abhi", "rust"), ("pads", "java"), ("swap", ".net")] {
+            db.put(k, v).unwrap();
+        }
+        for (k, v) in [("pooj", "pyth"), ("ashu", "scal")] {
+            db.put(k, v).unwrap();
+        }
+        assert_eq!(db.get_active_file(), 2);
+        assert_eq!(db.merge_progress(), MergeProgress::default());
+
+        // 2 files of 5 + 2 * 37 bytes are read & 4 records of 37 bytes are written
+        // along with their 36 byte hint entries. with a limit of 300 bytes a second,
+        // the 440 bytes of records take over 140 / 300 seconds past the initial burst
+        db.set_merge_rate_limit(300);
+        let start = std::time::Instant::now();
+        db.merge().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        assert_eq!(
+            db.merge_progress(),
+            MergeProgress {
+                running: false,
+                files_total: 2,
+                files_done: 2,
+                bytes_total: 2 * 79,
+                bytes_read: 2 * 79,
+                bytes_written: 4 * (37 + 36),
+            }
+        );
+        assert_eq!(db.merge_progress().fraction_done(), 1.0);
+        assert_eq!(db.get("pooj").unwrap(), Some("pyth".into()));
+        drop(db);
+
+        // reopening reads the 4 hint entries of 36 bytes & the 37 byte record in 2.
+        // with a limit of 100 bytes a second, that takes 81 / 100 seconds past the burst
+        let start = std::time::Instant::now();
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(100)
+            .with_restore_rate_limit(100)
+            .build()
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(700));
+        assert_eq!(db.get("ashu").unwrap(), Some("scal".into()));
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let cask = "compaction_filter_test";
+        let build = || {
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .with_compaction_filter(|key: &[u8], val: &[u8]| {
+                    if key.starts_with(b"old:") {
+                        FilterDecision::Remove
+                    } else if let Some(rest) = val.strip_prefix(b"v1:") {
+                        FilterDecision::ChangeValue([b"v2:", rest].concat().into())
+                    } else {
+                        FilterDecision::Keep
+                    }
+                })
+                .build()
+                .unwrap()
+        };
+        let db = build();
+        db.put("old:a", "x").unwrap();
+        db.put("abhi", "v1:rust").unwrap();
+        db.put("pads", "java").unwrap();
+        db.put("old:b", "y").unwrap();
+        db.put("swap", "v1:.net").unwrap();
+        assert_eq!(db.get_active_file(), 2);
+
+        db.merge().unwrap();
+        let check = |db: &HydraDB| {
+            assert_eq!(db.get("old:a").unwrap(), None);
+            assert_eq!(db.get("old:b").unwrap(), None);
+            assert_eq!(db.get("abhi").unwrap(), Some("v2:rust".into()));
+            assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+            // the active file isn't merged
+            assert_eq!(db.get("swap").unwrap(), Some("v1:.net".into()));
+            assert_eq!(db.key_dir.len(), 3);
+        };
+        check(&db);
+        drop(db);
+        check(&build());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_keydir_snapshot() {
+        let cask = "keydir_snapshot_db_test";
+        let build = |snapshots: bool| {
+            let builder = HydraDBBuilder::new().with_cask(cask).with_file_limit(100);
+            if snapshots {
+                builder.with_keydir_snapshot(Duration::from_secs(60))
+            } else {
+                builder
+            }
+            .build()
+            .unwrap()
+        };
+        let snapshot_path = format!("./{cask}/keydir.snapshot");
+
+        let db = build(true);
+        db.put("abhi", "rust").unwrap();
+        db.put("pads", "java").unwrap();
+        db.put("abhi", "cpp.").unwrap();
+        assert_eq!(db.get_active_file(), 1);
+        // a clean shutdown saves the snapshot
+        drop(db);
+        assert!(fs::exists(&snapshot_path).unwrap());
+
+        // break the superseded record in 0. a full scan would stop there & lose pads
+        let flip = |offset| {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .open(format!("./{cask}/0"))
+                .unwrap();
+            let mut byte = [0];
+            file.read_exact_at(&mut byte, offset).unwrap();
+            file.write_all_at(&[byte[0] ^ 1], offset).unwrap();
+        };
+        flip(5 + 29 + 4);
+
+        // records written after the snapshot get replayed on top of it
+        let db = build(false);
+        assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+        db.put("pooj", "pyth").unwrap();
+        db.del("abhi").unwrap();
+        drop(db);
+
+        let check = |db: &HydraDB| {
+            assert_eq!(db.get("abhi").unwrap(), None);
+            assert_eq!(db.get("pads").unwrap(), Some("java".into()));
+            assert_eq!(db.get("pooj").unwrap(), Some("pyth".into()));
+            assert_eq!(db.key_dir.len(), 2);
+        };
+        check(&build(false));
+
+        // a merge invalidates the snapshot
+        flip(5 + 29 + 4);
+        let db = build(true);
+        db.merge().unwrap();
+        assert!(!fs::exists(&snapshot_path).unwrap());
+        check(&db);
+        drop(db);
+        check(&build(false));
+
+        // a corrupt snapshot falls back to a full scan
+        fs::write(&snapshot_path, b"HYKS garbage").unwrap();
+        check(&build(false));
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_parallel_restore() {
+        let cask = "parallel_restore_test";
+        let build = |threads| {
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .with_restore_threads(threads)
+                .build()
+                .unwrap()
+        };
+
+        {
+            let db = build(1);
+            for i in 0..40 {
+                db.put(format!("key{}", i % 13), format!("v{i:03}"))
+                    .unwrap();
+            }
+            for i in (0..13).step_by(3) {
+                db.del(format!("key{i}")).unwrap();
+            }
+            // merged files restore from their hints
+            db.merge().unwrap();
+            for i in 0..10 {
+                db.put(format!("key{}", i * 2 % 13), "late").unwrap();
+            }
+            db.del("key4").unwrap();
+        }
+
+        let entries = |db: &HydraDB| {
+            let mut entries = BTreeMap::new();
+            db.key_dir
+                .for_each(|k, e| {
+                    entries.insert(k.to_vec(), (e.file_id, e.val_sz, e.val_pos, e.tstamp));
+                })
+                .unwrap();
+            entries
+        };
+
+        let db = build(1);
+        let expected = entries(&db);
+        let sequential = db.restore_stats();
+        assert_eq!(sequential.threads, 1);
+        assert_eq!(db.get("key4").unwrap(), None);
+        assert_eq!(db.get("key2").unwrap(), Some("late".into()));
+        drop(db);
+
+        // the workers finish in any order but the result is the same
+        for _ in 0..5 {
+            let db = build(4);
+            assert_eq!(entries(&db), expected);
+            let parallel = db.restore_stats();
+            assert_eq!(parallel.threads, 4);
+            assert_eq!(parallel.files, sequential.files);
+            assert_eq!(parallel.bytes, sequential.bytes);
+            assert_eq!(parallel.keys, expected.len());
+        }
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_compact_keydir() {
+        let cask = "compact_keydir_test";
+        let build = || {
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .with_keydir(KeyDirKind::Compact)
+                .with_keydir_snapshot(Duration::from_secs(60))
+                .build()
+                .unwrap()
+        };
+
+        let db = build();
+        db.put("abhi", "rust").unwrap();
+        db.put("pads", "java").unwrap();
+        db.put("swap", ".net").unwrap();
+        db.put("abhi", "cpp.").unwrap();
+        db.del("pads").unwrap();
+        assert!(db.put_if_absent("pooj", "pyth").unwrap());
+        assert!(db.compare_and_swap("swap", Some(b".net"), "go..").unwrap());
+        db.merge().unwrap();
+
+        let check = |db: &HydraDB| {
+            assert_eq!(db.get("abhi").unwrap(), Some("cpp.".into()));
+            assert_eq!(db.get("pads").unwrap(), None);
+            assert_eq!(db.get("swap").unwrap(), Some("go..".into()));
+            assert_eq!(db.get("pooj").unwrap(), Some("pyth".into()));
+
+            let memory = db.keydir_memory();
+            assert_eq!(memory.kind, KeyDirKind::Compact);
+            assert_eq!(memory.keys, 3);
+        };
+        check(&db);
+        drop(db);
+        // restored from the snapshot saved on drop
+        check(&build());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_disk_keydir() {
+        let cask = "disk_keydir_test";
+        let build = || {
+            HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .with_keydir(KeyDirKind::Disk { cache_size: 2 })
+                .build()
+                .unwrap()
+        };
+
+        let db = build();
+        for i in 0..20 {
+            db.put(format!("k{i:03}"), format!("v{i:03}")).unwrap();
+        }
+        for i in 0..10 {
+            db.del(format!("k{i:03}")).unwrap();
+        }
+        db.put("k015", "new.").unwrap();
+        db.merge().unwrap();
+
+        let check = |db: &HydraDB| {
+            assert_eq!(db.get("k005").unwrap(), None);
+            assert_eq!(db.get("k012").unwrap(), Some("v012".into()));
+            assert_eq!(db.get("k015").unwrap(), Some("new.".into()));
+            assert_eq!(db.list_all().unwrap().len(), 10);
+
+            let memory = db.keydir_memory();
+            assert_eq!(memory.kind, KeyDirKind::Disk { cache_size: 2 });
+            assert_eq!(memory.keys, 10);
+            assert!(memory.disk_bytes > 0);
+        };
+        check(&db);
+        drop(db);
+
+        // the index is kept, so no records are replayed
+        let db = build();
+        assert_eq!(db.restore_stats().bytes, 0);
+        check(&db);
+        db.put("k019", "new.").unwrap();
+        drop(db);
+        let db = build();
+        assert_eq!(db.restore_stats().bytes, 0);
+        assert_eq!(db.get("k019").unwrap(), Some("new.".into()));
+        drop(db);
+
+        // an index that wasn't closed, as after a crash, is rebuilt from the data files
+        fs::remove_file(format!("./{cask}/keydir.meta")).unwrap();
+        let db = build();
+        assert!(db.restore_stats().bytes > 0);
+        check(&db);
+        assert_eq!(db.get("k019").unwrap(), Some("new.".into()));
+        drop(db);
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let cask = "scan_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(1000)
+            .with_keydir(KeyDirKind::Ordered)
+            .build()
+            .unwrap();
+
+        // enough keys to take a few batches
+        for i in 0..300 {
+            db.put(format!("user:{i:03}"), format!("v{i}")).unwrap();
+        }
+        db.put("item:1", "book").unwrap();
+        db.put("user;", "next").unwrap();
+        db.put_with_ttl("user:050", "gone", Duration::from_millis(1))
+            .unwrap();
+        db.del("user:100").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        db.merge().unwrap();
+
+        let keys = |scan: crate::scan::Scan| {
+            scan.map(|res| String::from_utf8(res.unwrap().0.to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let users = keys(db.scan_prefix("user:"));
+        assert_eq!(users.len(), 298);
+        assert!(users.is_sorted());
+        assert!(!users.contains(&"user:050".into()) && !users.contains(&"user:100".into()));
+
+        assert_eq!(
+            keys(db.scan_prefix("user:").reverse().limit(3)),
+            ["user:299", "user:298", "user:297"]
+        );
+        assert_eq!(
+            keys(db.scan("user:098".."user:102")),
+            ["user:098", "user:099", "user:101"]
+        );
+        assert_eq!(keys(db.scan("user:299"..)), ["user:299", "user;"]);
+        assert_eq!(keys(db.scan(..="item:1")), ["item:1"]);
+
+        let (k, v) = db.scan_prefix("item").next().unwrap().unwrap();
+        assert_eq!((k, v), ("item:1".into(), "book".into()));
+        assert!(db.scan_prefix("nope").next().is_none());
+
+        // a merge may move the records of a batch that was already looked up
+        let mut scan = db.scan_prefix("user:");
+        assert_eq!(scan.next().unwrap().unwrap().0, "user:000");
+        db.put("user:300", "v300").unwrap();
+        db.merge().unwrap();
+        let rest: Vec<_> = scan.map(Result::unwrap).collect();
+        assert_eq!(rest.len(), 298);
+        for (k, v) in rest {
+            let i: usize = std::str::from_utf8(&k[5..]).unwrap().parse().unwrap();
+            assert_eq!(v, format!("v{i}"));
+        }
+
+        drop(db);
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+
+        // a hash keydir can't be scanned
+        let db = HydraDBBuilder::new().with_cask(cask).build().unwrap();
+        assert!(db.scan_prefix("user:").next().unwrap().is_err());
+        drop(db);
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_iter() {
+        let cask = "iter_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(1000)
+            .build()
+            .unwrap();
+        assert!(db.list_all().unwrap().is_empty());
+        assert!(db.iter().next().is_none());
+
+        for i in 0..500 {
+            db.put(format!("k{i:03}"), format!("v{i}")).unwrap();
+        }
+        db.put_with_ttl("gone", "soon", Duration::from_millis(1))
+            .unwrap();
+        db.del("k007").unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(db.keys().count(), 499);
+        assert_eq!(db.list_all().unwrap().len(), 499);
+        for res in db.iter() {
+            let (k, v) = res.unwrap();
+            let i: usize = std::str::from_utf8(&k[1..]).unwrap().parse().unwrap();
+            assert_eq!(v, format!("v{i}"));
+        }
+
+        // page through the keys while they are being written & merged
+        let mut cursor = Default::default();
+        let mut seen = vec![];
+        loop {
+            let mut keys = db.keys().after(cursor);
+            let page: Vec<_> = keys.by_ref().take(64).map(Result::unwrap).collect();
+            if page.is_empty() {
+                break;
+            }
+            cursor = keys.cursor();
+            seen.extend(page);
+
+            db.put(format!("n{}", seen.len()), "new.").unwrap();
+            db.merge().unwrap();
+        }
+        let old: HashSet<_> = seen.iter().filter(|k| k[0] == b'k').collect();
+        assert_eq!(old.len(), 499);
+        assert_eq!(seen.len(), HashSet::<&Bytes>::from_iter(&seen).len());
+
+        drop(db);
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let cask = "snapshot_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        for i in 0..10 {
+            db.put(format!("k{i}"), format!("old{i}")).unwrap();
+        }
+
+        // the snapshot opens its files outside the file cache
+        let stats = db.cache_stats();
+        let snapshot = db.snapshot().unwrap();
+        assert!(snapshot.pinned_files() > 1);
+        assert_eq!(db.cache_stats(), stats);
+        for i in 0..10 {
+            if i % 2 == 0 {
+                db.del(format!("k{i}")).unwrap();
+            } else {
+                db.put(format!("k{i}"), format!("new{i}")).unwrap();
+            }
+        }
+        db.put("k10", "new.").unwrap();
+        // the merge replaces & removes the files the snapshot reads from
+        db.merge().unwrap();
+        assert_eq!(db.get("k1").unwrap(), Some("new1".into()));
+        assert_eq!(db.get("k2").unwrap(), None);
+
+        assert_eq!(snapshot.get("k1").unwrap(), Some("old1".into()));
+        assert_eq!(snapshot.get("k2").unwrap(), Some("old2".into()));
+        assert_eq!(snapshot.get("k10").unwrap(), None);
+        let all: BTreeMap<_, _> = snapshot.iter().map(Result::unwrap).collect();
+        assert_eq!(all.len(), 10);
+        for i in 0..10 {
+            assert_eq!(all[&Bytes::from(format!("k{i}"))], format!("old{i}"));
+        }
+        assert_eq!(snapshot.keys().count(), 10);
+
+        drop(snapshot);
+        db.merge().unwrap();
+        assert_eq!(db.list_all().unwrap().len(), 6);
+
+        drop(db);
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_snapshot_during_merge() {
+        let cask = "snapshot_during_merge_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(200)
+            .build()
+            .unwrap();
+        for i in 0..20 {
+            db.put(format!("k{i:02}"), "v0").unwrap();
+        }
+
+        let merges = AtomicUsize::new(0);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut round = 1;
+                while !done.load(Ordering::Relaxed) {
+                    for i in 0..20 {
+                        db.put(format!("k{i:02}"), format!("v{round}")).unwrap();
+                    }
+                    db.merge().unwrap();
+                    merges.fetch_add(1, Ordering::Relaxed);
+                    round += 1;
+                }
+            });
+
+            for _ in 0..10 {
+                let snapshot = db.snapshot().unwrap();
+                let before: BTreeMap<_, _> = snapshot.iter().map(Result::unwrap).collect();
+                assert_eq!(before.len(), 20);
+
+                // the files the snapshot pinned are merged away under it
+                let seen = merges.load(Ordering::Relaxed);
+                while merges.load(Ordering::Relaxed) < seen + 2 {
+                    thread::yield_now();
+                }
+                let after: BTreeMap<_, _> = snapshot.iter().map(Result::unwrap).collect();
+                assert_eq!(after, before);
+                for (k, v) in &before {
+                    assert_eq!(snapshot.get(k).unwrap().as_ref(), Some(v));
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        drop(db);
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let cask = "multi_get_test";
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(200)
+            .build()
+            .unwrap();
+
+        // the values end up in several files
+        for i in 0..30 {
+            db.put(format!("k{i:02}"), format!("v{i:02}")).unwrap();
+        }
+        db.del("k05").unwrap();
+        db.put("k07", "new.").unwrap();
+        db.put_with_ttl("k09", "gone", Duration::from_millis(50))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let keys = [
+            "k28", "k03", "nope", "k05", "k07", "k03", "k09", "k00", "k17",
+        ];
+        let values = db.multi_get(&keys).unwrap();
+        assert_eq!(
+            values,
+            [
+                Some("v28".into()),
+                Some("v03".into()),
+                None,
+                None,
+                Some("new.".into()),
+                Some("v03".into()),
+                None,
+                Some("v00".into()),
+                Some("v17".into()),
+            ]
+        );
+
+        db.merge().unwrap();
+        let keys: Vec<_> = (0..30).rev().map(|i| format!("k{i:02}")).collect();
+        let values = db.multi_get(&keys).unwrap();
+        for (k, val) in keys.iter().zip(values) {
+            assert_eq!(val, db.get(k).unwrap());
+        }
+        assert!(db.multi_get::<&str>(&[]).unwrap().is_empty());
+
+        // an entry that points into a record header is corruption, which a read after
+        // a merge retries, rather than a panic
+        let entry = KeyDirEntry::new(1, 3, 2, 0, 0);
+        let err = record_span(FormatVersion::CURRENT, b"k00", &entry).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HydraError>(),
+            Some(&HydraError::Corruption {
+                file_id: 1,
+                offset: 2
+            })
+        );
+
+        drop(db);
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_interrupted_merge_recovered() {
+        let cask = "interrupted_merge_test";
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            db.put("abhi", "rust").unwrap();
+            db.put("pads", "java").unwrap();
+            db.put("swap", ".net").unwrap();
+            db.del("pads").unwrap();
+            db.put("pooj", "pyth").unwrap();
+            assert_eq!(db.get_active_file(), 2);
+        }
+
+        // a merge that crashed before writing its journal is rolled back
+        fs::write(format!("./{cask}/merge-1"), b"half merged").unwrap();
+        fs::write(format!("./{cask}/merge-1.hint"), b"half").unwrap();
+        let file_0 = fs::read(format!("./{cask}/0")).unwrap();
+        {
+            let db = HydraDBBuilder::new()
+                .with_cask(cask)
+                .with_file_limit(100)
+                .build()
+                .unwrap();
+            assert!(!fs::exists(format!("./{cask}/merge-1")).unwrap());
+            assert!(!fs::exists(format!("./{cask}/merge-1.hint")).unwrap());
+            assert_eq!(db.key_dir.len(), 3);
+
+            db.merge().unwrap();
+            assert!(!fs::exists(format!("./{cask}/merge.journal")).unwrap());
+        }
+
+        // a merge that crashed after publishing but before deleting its inputs is
+        // rolled forward. otherwise the stale input would bring "pads" back
+        fs::write(format!("./{cask}/0"), file_0).unwrap();
+        MergeJournal {
+            inputs: vec![0, 1],
+            outputs: vec![1],
+        }
+        .write(cask)
+        .unwrap();
+
+        let db = HydraDBBuilder::new()
+            .with_cask(cask)
+            .with_file_limit(100)
+            .build()
+            .unwrap();
+        assert!(!fs::exists(format!("./{cask}/0")).unwrap());
+        assert!(!fs::exists(format!("./{cask}/merge.journal")).unwrap());
+        assert_eq!(db.key_dir.len(), 3);
+        assert_eq!(db.get("pads").unwrap(), None);
+        assert_eq!(db.get("abhi").unwrap(), Some("rust".into()));
+        assert_eq!(db.get("pooj").unwrap(), Some("pyth".into()));
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
 }
@@ -0,0 +1,91 @@
+use crate::hydradb::HydraDB;
+use crate::key_dir::{Cursor, KeyDirIter};
+use crate::utils::now_millis;
+use anyhow::Result;
+use bytes::Bytes;
+
+/// a lazy iterator over the keys that haven't expired & their values. values are read
+/// as they are reached, & the keydir is walked a page at a time so that only a page of
+/// keys is held in memory. a keydir on disk is walked a part of it at a time instead
+#[derive(Debug)]
+pub struct Iter<'a> {
+    db: &'a HydraDB,
+    keys: KeyDirIter<'a>,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(db: &'a HydraDB) -> Self {
+        Self {
+            db,
+            keys: db.key_dir().iter(),
+        }
+    }
+
+    /// resumes from `cursor`, as returned by `cursor` of an earlier iterator
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.keys = self.keys.after(cursor);
+        self
+    }
+
+    /// the position right after the last key yielded, to resume from later
+    pub fn cursor(&self) -> Cursor {
+        self.keys.cursor().clone()
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for res in self.keys.by_ref() {
+            let (k, entry) = match res {
+                Ok(found) => found,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.db.read_entry(&k, &entry) {
+                Ok(Some(val)) => return Some(Ok((k, val))),
+                // expired or deleted since the page was looked up
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// a lazy iterator over the keys that haven't expired
+#[derive(Debug)]
+pub struct Keys<'a> {
+    keys: KeyDirIter<'a>,
+}
+
+impl<'a> Keys<'a> {
+    pub(crate) fn new(db: &'a HydraDB) -> Self {
+        Self {
+            keys: db.key_dir().iter(),
+        }
+    }
+
+    /// resumes from `cursor`, as returned by `cursor` of an earlier iterator
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.keys = self.keys.after(cursor);
+        self
+    }
+
+    /// the position right after the last key yielded, to resume from later
+    pub fn cursor(&self) -> Cursor {
+        self.keys.cursor().clone()
+    }
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = now_millis();
+        self.keys
+            .by_ref()
+            .find(|res| !matches!(res, Ok((_, entry)) if entry.is_expired(now)))
+            .map(|res| res.map(|(k, _)| k))
+    }
+}
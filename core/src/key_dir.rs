@@ -1,78 +1,551 @@
 // use std::collections::HashMap;
 
+use crate::compact_key_dir::CompactKeyDir;
+use crate::disk_key_dir::DiskKeyDir;
+use crate::frozen_key_dir::{Changes, FrozenKeyDir};
+use crate::ordered_key_dir::OrderedKeyDir;
+use anyhow::{Result, bail};
 use bytes::Bytes;
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt::Debug;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct KeyDirEntry {
     pub file_id: usize,
     pub val_sz: u32,
     pub val_pos: u64,
-    pub tstamp: u32,
+    pub tstamp: u64,
+    /// time (in millis) at which the entry expires, 0 if never
+    pub expiry: u64,
 }
 
 impl KeyDirEntry {
-    pub fn new(file_id: usize, val_sz: u32, val_pos: u64, tstamp: u32) -> Self {
+    pub fn new(file_id: usize, val_sz: u32, val_pos: u64, tstamp: u64, expiry: u64) -> Self {
         Self {
             file_id,
             val_sz,
             val_pos,
             tstamp,
+            expiry,
         }
     }
+
+    /// checks if the entry has expired as of `now` (in millis)
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiry != 0 && self.expiry <= now
+    }
 }
 
-#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+/// where a keydir keeps its entries
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDirKind {
+    /// a concurrent hash map of keys to entries
+    #[default]
+    Standard,
+    /// packed entries & keys kept in shared arenas, for very large key counts
+    Compact,
+    /// a sorted map of keys to entries, for range & prefix scans
+    Ordered,
+    /// a hash index on disk with an in-memory cache of up to `cache_size` entries,
+    /// for key sets that don't fit in memory
+    Disk { cache_size: usize },
+}
+
+/// an estimate of the memory a keydir takes. allocator overhead isn't counted, so the
+/// standard keydir with its allocation per key takes somewhat more than reported
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeyDirMemory {
+    pub kind: KeyDirKind,
+    pub keys: usize,
+    /// bytes taken by the keys themselves
+    pub key_bytes: u64,
+    /// bytes taken by the hash tables, entries included
+    pub table_bytes: u64,
+    /// bytes taken on disk, for a keydir that lives there
+    pub disk_bytes: u64,
+}
+
+impl KeyDirMemory {
+    /// bytes taken in memory
+    pub fn total_bytes(&self) -> u64 {
+        self.key_bytes + self.table_bytes
+    }
+}
+
+/// where a keydir keeps its entries. every call must be atomic with respect to the
+/// others, as the keydir is shared by all readers & writers. only a backend on disk
+/// can fail to read or write its entries
+pub trait KeyDirBackend: Send + Sync + Debug {
+    fn get(&self, k: &[u8]) -> Result<Option<KeyDirEntry>>;
+
+    /// calls `f` with the entry of `k`, if any, & stores whatever it leaves there.
+    /// `None` deletes the key. nothing else may change the key meanwhile
+    fn update(&self, k: &[u8], f: &mut dyn FnMut(&mut Option<KeyDirEntry>)) -> Result<()>;
+
+    /// calls `f` with every key & its entry
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()>;
+
+    /// how many parts the keys are split into. a key never moves to another part, so
+    /// the keys can be walked a part at a time while others are written
+    fn parts(&self) -> usize {
+        1
+    }
+
+    /// the part that `k` is in
+    fn part_of(&self, _k: &[u8]) -> usize {
+        0
+    }
+
+    /// how many keys a walk takes at a time. a page of a backend that keeps no key
+    /// order looks at its whole part, so a backend where that's slow takes more
+    fn page_size(&self) -> usize {
+        PAGE
+    }
+
+    /// calls `f` with every key in `part` & its entry
+    fn for_each_in(&self, _part: usize, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        self.for_each(f)
+    }
+
+    /// whether `range` walks the keys in order
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
+    /// calls `f` with the keys between `start` & `end` and their entries in key order,
+    /// or in reverse, until it returns false. fails if the backend keeps no key order
+    fn range(
+        &self,
+        _start: Bound<&[u8]>,
+        _end: Bound<&[u8]>,
+        _reverse: bool,
+        _f: &mut dyn FnMut(&[u8], &KeyDirEntry) -> bool,
+    ) -> Result<()> {
+        bail!("the keydir keeps no key order, range scans need KeyDirKind::Ordered")
+    }
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// removes every key
+    fn clear(&self) -> Result<()>;
+
+    fn memory(&self) -> KeyDirMemory;
+
+    /// whether the entries outlive the backend, so that a keydir snapshot only needs to
+    /// tell where in the cask they are up to
+    fn is_durable(&self) -> bool {
+        false
+    }
+
+    /// whether the backend came back with the entries it had when it was last closed
+    fn is_restored(&self) -> bool {
+        false
+    }
+
+    /// puts `entry` for `k`. returns the entry it replaced, if any
+    fn put(&self, k: Bytes, entry: KeyDirEntry) -> Result<Option<KeyDirEntry>> {
+        let mut old = None;
+        self.update(&k, &mut |cur| old = cur.replace(entry.clone()))?;
+        Ok(old)
+    }
+
+    /// deletes `k`. returns its entry, if any
+    fn del(&self, k: &[u8]) -> Result<Option<KeyDirEntry>> {
+        let mut old = None;
+        self.update(k, &mut |cur| old = cur.take())?;
+        Ok(old)
+    }
+}
+
+impl KeyDirBackend for DashMap<Bytes, KeyDirEntry> {
+    fn get(&self, k: &[u8]) -> Result<Option<KeyDirEntry>> {
+        Ok(DashMap::get(self, k).map(|entry| entry.clone()))
+    }
+
+    fn update(&self, k: &[u8], f: &mut dyn FnMut(&mut Option<KeyDirEntry>)) -> Result<()> {
+        match self.entry(Bytes::copy_from_slice(k)) {
+            dashmap::Entry::Occupied(mut occupied) => {
+                let mut cur = Some(occupied.get().clone());
+                f(&mut cur);
+                match cur {
+                    Some(new) => *occupied.get_mut() = new,
+                    None => {
+                        occupied.remove();
+                    }
+                }
+            }
+            dashmap::Entry::Vacant(vacant) => {
+                let mut cur = None;
+                f(&mut cur);
+                if let Some(new) = cur {
+                    vacant.insert(new);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        for entry in self.iter() {
+            f(entry.key(), entry.value());
+        }
+        Ok(())
+    }
+
+    // a part is a shard of the map, so a walk over it doesn't look at any other
+    fn parts(&self) -> usize {
+        self.shards().len()
+    }
+
+    fn part_of(&self, k: &[u8]) -> usize {
+        self.determine_map(k)
+    }
+
+    fn for_each_in(&self, part: usize, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        let shard = self.shards()[part].read();
+        // SAFETY: the buckets are only read while the shard is locked, so none of them
+        // can be moved or freed meanwhile
+        unsafe {
+            for bucket in shard.iter() {
+                let (k, entry) = bucket.as_ref();
+                f(k, entry.get());
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        DashMap::len(self)
+    }
+
+    fn clear(&self) -> Result<()> {
+        DashMap::clear(self);
+        Ok(())
+    }
+
+    fn memory(&self) -> KeyDirMemory {
+        let mut key_bytes = 0;
+        self.iter()
+            .for_each(|entry| key_bytes += entry.key().len() as u64);
+        // every bucket has a control byte besides its key & entry
+        let bucket = size_of::<(Bytes, KeyDirEntry)>() + 1;
+
+        KeyDirMemory {
+            kind: KeyDirKind::Standard,
+            keys: DashMap::len(self),
+            key_bytes,
+            table_bytes: (self.capacity() * bucket) as u64,
+            disk_bytes: 0,
+        }
+    }
+
+    // no need to copy the key to look it up
+    fn put(&self, k: Bytes, entry: KeyDirEntry) -> Result<Option<KeyDirEntry>> {
+        Ok(self.insert(k, entry))
+    }
+
+    fn del(&self, k: &[u8]) -> Result<Option<KeyDirEntry>> {
+        Ok(self.remove(k).map(|(_, entry)| entry))
+    }
+}
+
+/// how many keys a keydir is walked in at a time
+pub(crate) const PAGE: usize = 1024;
+
+fn standard_backend() -> Box<dyn KeyDirBackend> {
+    Box::new(DashMap::<Bytes, KeyDirEntry>::new())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct KeyDir {
-    #[serde(skip)]
-    kv_store: DashMap<Bytes, KeyDirEntry>,
+    #[serde(skip, default = "standard_backend")]
+    kv_store: Box<dyn KeyDirBackend>,
     // kv_store: HashMap<Bytes, KeyDirEntry>,
+    /// the changes seen by every frozen view of the store that is still around
+    #[serde(skip)]
+    frozen: RwLock<Vec<Arc<Changes>>>,
+}
+
+impl Default for KeyDir {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KeyDir {
     /// constructs a new in-mem store
     pub fn new() -> Self {
+        Self::with_backend(standard_backend())
+    }
+
+    /// constructs a new store of the given kind. a store on disk keeps its files in
+    /// the directory `dir`
+    pub fn open(kind: KeyDirKind, dir: impl AsRef<Path>) -> Result<Self> {
+        let kv_store: Box<dyn KeyDirBackend> = match kind {
+            KeyDirKind::Standard => standard_backend(),
+            KeyDirKind::Compact => Box::new(CompactKeyDir::default()),
+            KeyDirKind::Ordered => Box::new(OrderedKeyDir::default()),
+            KeyDirKind::Disk { cache_size } => Box::new(DiskKeyDir::open(dir, cache_size)?),
+        };
+        Ok(Self::with_backend(kv_store))
+    }
+
+    /// constructs a store that keeps its entries in `backend`
+    pub fn with_backend(backend: Box<dyn KeyDirBackend>) -> Self {
         Self {
-            kv_store: DashMap::new(),
-            // kv_store: HashMap::new(),
+            kv_store: backend,
+            frozen: RwLock::default(),
         }
     }
 
-    /// puts the key-value pair in the store
-    pub fn put(&self, k: impl Into<Bytes>, v: KeyDirEntry) {
-        self.kv_store.insert(k.into(), v);
+    /// puts the key-value pair in the store. returns the entry it replaced, if any
+    pub fn put(&self, k: impl Into<Bytes>, v: KeyDirEntry) -> Result<Option<KeyDirEntry>> {
+        let k = k.into();
+        let frozen = self.frozen.read().unwrap();
+        if frozen.is_empty() {
+            return self.kv_store.put(k, v);
+        }
+
+        let mut old = None;
+        self.kv_store.update(&k, &mut |entry| {
+            self.record(&frozen, &k, entry);
+            old = entry.replace(v.clone());
+        })?;
+        Ok(old)
     }
 
     /// gets the value for given key `k`
-    pub fn get(&self, k: impl AsRef<[u8]>) -> Option<KeyDirEntry> {
-        self.kv_store.get(k.as_ref()).map(|entry| entry.clone())
-        // self.kv_store.get(k.as_ref()).cloned()
+    pub fn get(&self, k: impl AsRef<[u8]>) -> Result<Option<KeyDirEntry>> {
+        self.kv_store.get(k.as_ref())
+    }
+
+    /// deletes the given key `k`. returns its entry, if any
+    pub fn del(&self, k: impl AsRef<[u8]>) -> Result<Option<KeyDirEntry>> {
+        let k = k.as_ref();
+        let frozen = self.frozen.read().unwrap();
+        if frozen.is_empty() {
+            return self.kv_store.del(k);
+        }
+
+        let mut old = None;
+        self.kv_store.update(k, &mut |entry| {
+            self.record(&frozen, k, entry);
+            old = entry.take();
+        })?;
+        Ok(old)
+    }
+
+    /// replaces the entry of the given key `k` with `new` only if it still points at
+    /// the record at `val_pos` in `file_id`. returns whether it was replaced
+    pub fn compare_and_set(
+        &self,
+        k: impl AsRef<[u8]>,
+        file_id: usize,
+        val_pos: u64,
+        new: KeyDirEntry,
+    ) -> Result<bool> {
+        let mut swapped = false;
+        self.update(k.as_ref(), |entry| {
+            if let Some(entry) = entry
+                && entry.file_id == file_id
+                && entry.val_pos == val_pos
+            {
+                *entry = new.clone();
+                swapped = true;
+            }
+        })?;
+        Ok(swapped)
+    }
+
+    /// deletes the given key `k` only if its entry still points at the record at
+    /// `val_pos` in `file_id`. returns whether it was deleted
+    pub fn compare_and_del(
+        &self,
+        k: impl AsRef<[u8]>,
+        file_id: usize,
+        val_pos: u64,
+    ) -> Result<bool> {
+        self.del_if(k.as_ref(), |entry| {
+            entry.file_id == file_id && entry.val_pos == val_pos
+        })
+    }
+
+    /// deletes the given key `k` if its entry has expired as of `now`
+    pub fn del_expired(&self, k: impl AsRef<[u8]>, now: u64) -> Result<bool> {
+        self.del_if(k.as_ref(), |entry| entry.is_expired(now))
+    }
+
+    fn del_if(&self, k: &[u8], f: impl Fn(&KeyDirEntry) -> bool) -> Result<bool> {
+        let mut deleted = false;
+        self.update(k, |entry| {
+            if entry.as_ref().is_some_and(&f) {
+                *entry = None;
+                deleted = true;
+            }
+        })?;
+        Ok(deleted)
     }
 
-    /// deletes the given key `k`
-    pub fn del(&self, k: impl AsRef<[u8]>) {
-        self.kv_store.remove(k.as_ref());
+    /// calls `f` with the entry of `k`, if any, & stores whatever it leaves there
+    fn update(&self, k: &[u8], mut f: impl FnMut(&mut Option<KeyDirEntry>)) -> Result<()> {
+        let frozen = self.frozen.read().unwrap();
+        self.kv_store.update(k, &mut |entry| {
+            self.record(&frozen, k, entry);
+            f(entry)
+        })
+    }
+
+    /// records the entry `k` has before it changes for every frozen view in `frozen`.
+    /// must be called before the change is stored, so that a reader who sees the change
+    /// also sees the record
+    fn record(&self, frozen: &[Arc<Changes>], k: &[u8], entry: &Option<KeyDirEntry>) {
+        if frozen.is_empty() {
+            return;
+        }
+        let part = self.kv_store.part_of(k);
+        for changes in frozen {
+            changes.record(part, k, entry);
+        }
+    }
+
+    /// freezes a view of the store as it is now. the store records the entries that
+    /// keys had before they change for as long as the view is around
+    pub fn freeze(&self) -> FrozenKeyDir<'_> {
+        let changes = Arc::new(Changes::default());
+        self.frozen.write().unwrap().push(changes.clone());
+        FrozenKeyDir::new(self, changes)
+    }
+
+    /// stops recording changes for a frozen view that went away
+    pub(crate) fn thaw(&self, changes: &Arc<Changes>) {
+        self.frozen
+            .write()
+            .unwrap()
+            .retain(|frozen| !Arc::ptr_eq(frozen, changes));
+    }
+
+    pub(crate) fn parts(&self) -> usize {
+        self.kv_store.parts()
+    }
+
+    pub(crate) fn part_of(&self, k: &[u8]) -> usize {
+        self.kv_store.part_of(k)
+    }
+
+    /// how many keys a walk over the store takes at a time
+    pub(crate) fn page_size(&self) -> usize {
+        self.kv_store.page_size()
+    }
+
+    pub(crate) fn is_durable(&self) -> bool {
+        self.kv_store.is_durable()
+    }
+
+    pub(crate) fn is_restored(&self) -> bool {
+        self.kv_store.is_restored()
     }
 
     /// checks if the given key `k` is present
-    pub fn has_key(&self, k: impl AsRef<[u8]>) -> bool {
-        self.kv_store.contains_key(k.as_ref())
+    pub fn has_key(&self, k: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self.kv_store.get(k.as_ref())?.is_some())
+    }
+
+    /// iterates over all the keys in the in-mem store lazily
+    pub fn keys(&self) -> impl Iterator<Item = Result<Bytes>> + '_ {
+        self.iter().map(|res| res.map(|(k, _)| k))
+    }
+
+    /// calls `f` with every key & its entry in the in-mem store
+    pub fn for_each(&self, mut f: impl FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        self.kv_store.for_each(&mut f)
+    }
+
+    /// returns up to `limit` of the keys after `cursor` along with their entries, in
+    /// the order the keydir is walked in, and the part of the keydir they are in. a page
+    /// never spans two parts, so it may hold fewer keys than there are left. it is
+    /// empty only once the walk is over. a keydir that keeps no key order looks at the
+    /// whole part for every page, but only holds `limit` keys of it
+    pub fn page(
+        &self,
+        cursor: &Cursor,
+        limit: usize,
+    ) -> Result<(usize, Vec<(Bytes, KeyDirEntry)>)> {
+        let after = cursor.key.as_deref();
+        if limit == 0 {
+            return Ok((cursor.part, vec![]));
+        }
+        if self.kv_store.is_ordered() {
+            let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+            let page = self.range(start, Bound::Unbounded, false, limit)?;
+            return Ok((0, page));
+        }
+
+        // unordered keydirs are walked a part at a time, each in key order. only the
+        // `limit` lowest keys of a part are kept, but the whole part is scanned for them
+        let mut after = after;
+        for part in cursor.part..self.kv_store.parts() {
+            let mut page = BinaryHeap::new();
+            self.kv_store.for_each_in(part, &mut |k, entry| {
+                if after.is_some_and(|after| k <= after) {
+                    return;
+                }
+                if page.len() == limit {
+                    match page.peek() {
+                        Some(PageEntry(top, _)) if k < &top[..] => {
+                            page.pop();
+                        }
+                        _ => return,
+                    }
+                }
+                page.push(PageEntry(Bytes::copy_from_slice(k), entry.clone()));
+            })?;
+            if !page.is_empty() {
+                let page = page.into_sorted_vec();
+                return Ok((part, page.into_iter().map(|e| (e.0, e.1)).collect()));
+            }
+            after = None;
+        }
+        Ok((self.kv_store.parts(), vec![]))
+    }
+
+    /// iterates over every key & its entry lazily, a page at a time
+    pub fn iter(&self) -> KeyDirIter<'_> {
+        KeyDirIter {
+            key_dir: self,
+            cursor: Cursor::default(),
+            page: VecDeque::new(),
+            done: false,
+        }
     }
 
-    /// returns all the keys in the in-mem store
-    pub fn keys(&self) -> Option<Vec<Bytes>> {
-        if self.kv_store.is_empty() {
-            None
-        } else {
-            Some(
-                self.kv_store
-                    .iter()
-                    .map(|entry| entry.key().clone())
-                    .collect(),
-            )
-            // Some(self.kv_store.keys().cloned().collect())
+    /// returns up to `limit` keys between `start` & `end` along with their entries, in
+    /// key order or in reverse. fails if the store keeps no key order
+    pub fn range(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Bytes, KeyDirEntry)>> {
+        let mut entries = vec![];
+        if limit > 0 {
+            self.kv_store.range(start, end, reverse, &mut |k, entry| {
+                entries.push((Bytes::copy_from_slice(k), entry.clone()));
+                entries.len() < limit
+            })?;
         }
+        Ok(entries)
     }
 
     /// returns the num of entries in the in-mem store
@@ -81,30 +554,317 @@ impl KeyDir {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.kv_store.len() == 0
+        self.len() == 0
+    }
+
+    /// removes every key
+    pub fn clear(&self) -> Result<()> {
+        self.kv_store.clear()
+    }
+
+    /// estimates the memory taken by the store
+    pub fn memory(&self) -> KeyDirMemory {
+        self.kv_store.memory()
+    }
+}
+
+/// a position in a walk over a keydir. a walk that resumes from it yields every key
+/// that has been there all along & hasn't been yielded yet exactly once, no matter
+/// what was written in between. it's only meaningful to the keydir it came from
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub(crate) part: usize,
+    /// the last key yielded in `part`, if any
+    pub(crate) key: Option<Bytes>,
+}
+
+/// a key & its entry in a page, ordered by the key alone
+struct PageEntry(Bytes, KeyDirEntry);
+
+impl PartialEq for PageEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for PageEntry {}
+
+impl PartialOrd for PageEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PageEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// a lazy iterator over the keys of a keydir & their entries
+#[derive(Debug)]
+pub struct KeyDirIter<'a> {
+    key_dir: &'a KeyDir,
+    cursor: Cursor,
+    page: VecDeque<(Bytes, KeyDirEntry)>,
+    done: bool,
+}
+
+impl KeyDirIter<'_> {
+    /// resumes the walk from `cursor`
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.cursor = cursor;
+        self.page.clear();
+        self.done = false;
+        self
+    }
+
+    /// the position right after the last key yielded
+    pub fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+impl Iterator for KeyDirIter<'_> {
+    type Item = Result<(Bytes, KeyDirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            let limit = self.key_dir.page_size();
+            // a failed page is tried again by the next call
+            let (part, page) = match self.key_dir.page(&self.cursor, limit) {
+                Ok(page) => page,
+                Err(e) => return Some(Err(e)),
+            };
+            self.done = page.is_empty();
+            self.cursor.part = part;
+            self.page.extend(page);
+        }
+
+        let (k, entry) = self.page.pop_front()?;
+        self.cursor.key = Some(k.clone());
+        Some(Ok((k, entry)))
+    }
+}
+
+/// checks if no key can lie between `start` & `end`
+pub(crate) fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{KeyDir, KeyDirEntry};
+    use std::ops::Bound;
+
+    use super::{Cursor, KeyDir, KeyDirEntry, KeyDirKind};
+
+    const KINDS: [KeyDirKind; 3] = [
+        KeyDirKind::Standard,
+        KeyDirKind::Compact,
+        KeyDirKind::Ordered,
+    ];
 
     #[test]
     fn put_test() {
-        let store = KeyDir::new();
-        store.put("abhi", KeyDirEntry::new(1, 5, 1, 0));
-        store.put("pads", KeyDirEntry::new(1, 9, 2, 0));
-        store.put("ashu", KeyDirEntry::new(1, 5, 3, 0));
-        assert_eq!(store.len(), 3);
+        for kind in KINDS {
+            let store = KeyDir::open(kind, ".").unwrap();
+            store.put("abhi", KeyDirEntry::new(1, 5, 1, 0, 0)).unwrap();
+            store.put("pads", KeyDirEntry::new(1, 9, 2, 0, 0)).unwrap();
+            store.put("ashu", KeyDirEntry::new(1, 5, 3, 0, 0)).unwrap();
+            assert_eq!(store.len(), 3);
+        }
     }
 
     #[test]
     fn del_test() {
-        let store = KeyDir::new();
-        store.put("abhi", KeyDirEntry::new(1, 5, 1, 0));
-        store.put("pads", KeyDirEntry::new(1, 9, 2, 0));
-        store.del("abhi");
-        store.put("ashu", KeyDirEntry::new(1, 5, 3, 0));
-        assert_eq!(store.len(), 2);
+        for kind in KINDS {
+            let store = KeyDir::open(kind, ".").unwrap();
+            store.put("abhi", KeyDirEntry::new(1, 5, 1, 0, 0)).unwrap();
+            store.put("pads", KeyDirEntry::new(1, 9, 2, 0, 0)).unwrap();
+            store.del("abhi").unwrap();
+            store.put("ashu", KeyDirEntry::new(1, 5, 3, 0, 0)).unwrap();
+            assert_eq!(store.len(), 2);
+        }
+    }
+
+    #[test]
+    fn compare_and_set_test() {
+        for kind in KINDS {
+            let store = KeyDir::open(kind, ".").unwrap();
+            store.put("abhi", KeyDirEntry::new(1, 5, 1, 0, 0)).unwrap();
+
+            assert!(
+                !store
+                    .compare_and_set("abhi", 1, 2, KeyDirEntry::new(2, 5, 1, 0, 0))
+                    .unwrap()
+            );
+            assert!(
+                !store
+                    .compare_and_set("pads", 1, 1, KeyDirEntry::new(2, 5, 1, 0, 0))
+                    .unwrap()
+            );
+            assert!(!store.has_key("pads").unwrap());
+            assert!(
+                store
+                    .compare_and_set("abhi", 1, 1, KeyDirEntry::new(2, 5, 7, 0, 0))
+                    .unwrap()
+            );
+
+            let entry = store.get("abhi").unwrap().unwrap();
+            assert_eq!((entry.file_id, entry.val_pos), (2, 7));
+
+            assert!(!store.compare_and_del("abhi", 1, 1).unwrap());
+            assert!(store.compare_and_del("abhi", 2, 7).unwrap());
+            assert!(!store.has_key("abhi").unwrap());
+        }
+    }
+
+    #[test]
+    fn memory_test() {
+        let [standard, compact] = [KeyDirKind::Standard, KeyDirKind::Compact].map(|kind| {
+            let store = KeyDir::open(kind, ".").unwrap();
+            for i in 0..10_000 {
+                store
+                    .put(format!("user:{i:08}"), KeyDirEntry::new(3, 100, i, i, 0))
+                    .unwrap();
+            }
+            assert_eq!(store.keys().count(), 10_000);
+            store.memory()
+        });
+
+        assert_eq!((standard.keys, compact.keys), (10_000, 10_000));
+        assert_eq!(compact.kind, KeyDirKind::Compact);
+        assert!(standard.key_bytes >= 10_000 * 13);
+        assert!(compact.total_bytes() < standard.total_bytes() * 3 / 4);
+    }
+
+    #[test]
+    fn range_test() {
+        let store = KeyDir::open(KeyDirKind::Ordered, ".").unwrap();
+        for k in ["user:2", "user:10", "user:1", "item:1", "user;"] {
+            store.put(k, KeyDirEntry::new(1, 5, 1, 0, 0)).unwrap();
+        }
+        let keys = |entries: Vec<(bytes::Bytes, KeyDirEntry)>| {
+            entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        };
+
+        let users = (
+            Bound::Included(&b"user:"[..]),
+            Bound::Excluded(&b"user;"[..]),
+        );
+        assert_eq!(
+            keys(store.range(users.0, users.1, false, 10).unwrap()),
+            ["user:1", "user:10", "user:2"]
+        );
+        assert_eq!(
+            keys(store.range(users.0, users.1, true, 2).unwrap()),
+            ["user:2", "user:10"]
+        );
+        assert_eq!(
+            keys(
+                store
+                    .range(Bound::Excluded(b"user:10"), Bound::Unbounded, false, 10)
+                    .unwrap()
+            ),
+            ["user:2", "user;"]
+        );
+        // a range that ends before it starts is empty
+        assert!(
+            store
+                .range(Bound::Included(b"z"), Bound::Excluded(b"a"), false, 10)
+                .unwrap()
+                .is_empty()
+        );
+
+        // hash keydirs have no order to scan by
+        let store = KeyDir::open(KeyDirKind::Standard, ".").unwrap();
+        assert!(
+            store
+                .range(Bound::Unbounded, Bound::Unbounded, false, 10)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn iter_test() {
+        for kind in KINDS {
+            let store = KeyDir::open(kind, ".").unwrap();
+            assert_eq!(store.iter().count(), 0);
+            for i in 0..1000 {
+                store
+                    .put(format!("key{i:04}"), KeyDirEntry::new(1, 5, i, 0, 0))
+                    .unwrap();
+            }
+
+            let mut iter = store.iter();
+            let mut seen: Vec<_> = iter.by_ref().take(300).map(|res| res.unwrap().0).collect();
+            let cursor = iter.cursor().clone();
+            drop(iter);
+
+            // writes between pages don't make the walk skip or repeat any key that is
+            // there all along
+            for k in &seen[..100] {
+                store.del(k).unwrap();
+            }
+            for i in 1000..1200 {
+                store
+                    .put(format!("key{i:04}"), KeyDirEntry::new(1, 5, i, 0, 0))
+                    .unwrap();
+            }
+            let rest: Vec<_> = store
+                .iter()
+                .after(cursor)
+                .map(|res| res.unwrap().0)
+                .collect();
+            seen.extend(rest);
+
+            let mut unique = seen.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), seen.len());
+            for i in 0..1000 {
+                assert!(unique.binary_search(&format!("key{i:04}").into()).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn page_test() {
+        for kind in KINDS {
+            let store = KeyDir::open(kind, ".").unwrap();
+            for i in 0..500 {
+                store
+                    .put(format!("key{i:04}"), KeyDirEntry::new(1, 5, i, 0, 0))
+                    .unwrap();
+            }
+
+            // small pages resume within a part & keep each part in key order
+            let (mut cursor, mut seen) = (Cursor::default(), vec![]);
+            loop {
+                let (part, page) = store.page(&cursor, 7).unwrap();
+                if page.is_empty() {
+                    break;
+                }
+                assert!(page.len() <= 7);
+                assert!(page.is_sorted_by(|(a, _), (b, _)| a < b));
+                if part == cursor.part {
+                    assert!(cursor.key.as_ref().is_none_or(|k| *k < page[0].0));
+                }
+                cursor = Cursor {
+                    part,
+                    key: page.last().map(|(k, _)| k.clone()),
+                };
+                seen.extend(page.into_iter().map(|(k, _)| k));
+            }
+
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), 500);
+        }
     }
 }
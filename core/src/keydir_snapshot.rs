@@ -0,0 +1,333 @@
+use crate::key_dir::{KeyDir, KeyDirEntry};
+use crate::merge_journal::remove_if_exists;
+use crate::utils::{data_file_ids, now_millis, sync_dir};
+use anyhow::{Result, bail};
+use crc32fast::Hasher;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+
+/// name of the snapshot in a cask directory
+const SNAPSHOT: &str = "keydir.snapshot";
+
+const MAGIC: &[u8; 4] = b"HYKS";
+const VERSION: u8 = 2;
+
+/// key size that marks the end of the entries
+const END: u32 = u32::MAX;
+
+/// writes everything through a crc
+struct CrcWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> CrcWriter<W> {
+    fn put(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.inner.write_all(buf)?;
+        Ok(())
+    }
+}
+
+/// reads everything through a crc
+struct CrcReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> CrcReader<R> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        // a corrupt length shouldn't make us allocate more than the file holds
+        let mut buf = vec![];
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+}
+
+/// a point in the history of a cask that a persisted keydir describes: every record
+/// before `offset` in the active file `active_id` & in the files before it.
+///
+/// the files before the active one are immutable, so the snapshot stays valid for as
+/// long as they are exactly the ones it lists. only a merge changes them, and a merge
+/// removes the snapshot before it starts publishing.
+///
+/// the snapshot of a keydir that keeps its entries itself, on disk, holds no entries.
+/// it only tells where in the cask they are up to
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyDirSnapshot {
+    /// the file that was active when the snapshot was taken
+    pub active_id: usize,
+    /// how much of the active file the snapshot covers
+    pub offset: u64,
+    /// ids & lengths of the files before the active one
+    pub files: Vec<(usize, u64)>,
+}
+
+impl KeyDirSnapshot {
+    /// describes the cask with `active_id` being written at `offset`
+    pub fn new(cask: &str, active_id: usize, offset: u64) -> Result<Self> {
+        let mut files = vec![];
+        for file_id in data_file_ids(format!("./{cask}"))? {
+            if file_id < active_id {
+                files.push((file_id, fs::metadata(format!("./{cask}/{file_id}"))?.len()));
+            }
+        }
+
+        Ok(Self {
+            active_id,
+            offset,
+            files,
+        })
+    }
+
+    /// durably writes the snapshot along with the entries of `key_dir`, unless it keeps
+    /// them itself, replacing any older one. expired entries are left out
+    pub fn write(&self, cask: &str, key_dir: &KeyDir) -> Result<()> {
+        let temp = format!("./{cask}/{SNAPSHOT}.tmp");
+        let mut out = CrcWriter {
+            inner: BufWriter::new(File::create(&temp)?),
+            hasher: Hasher::new(),
+        };
+
+        let held = key_dir.is_durable();
+        out.put(MAGIC)?;
+        out.put(&[VERSION, held as u8])?;
+        out.put(&(self.active_id as u64).to_be_bytes())?;
+        out.put(&self.offset.to_be_bytes())?;
+        out.put(&(self.files.len() as u32).to_be_bytes())?;
+        for &(file_id, len) in &self.files {
+            out.put(&(file_id as u64).to_be_bytes())?;
+            out.put(&len.to_be_bytes())?;
+        }
+
+        if !held {
+            let now = now_millis();
+            let mut res = Ok(());
+            key_dir.for_each(|k, entry| {
+                if res.is_ok() && !entry.is_expired(now) {
+                    res = write_entry(&mut out, k, entry);
+                }
+            })?;
+            res?;
+        }
+        out.put(&END.to_be_bytes())?;
+
+        let crc = out.hasher.finalize();
+        let mut file = out.inner;
+        file.write_all(&crc.to_be_bytes())?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        fs::rename(temp, format!("./{cask}/{SNAPSHOT}"))?;
+        sync_dir(format!("./{cask}"))
+    }
+
+    /// reads the snapshot of the cask, if there is one, putting its entries in `key_dir`
+    /// in place of what it holds. fails if the snapshot is corrupt, in which case
+    /// `key_dir` may hold some of them, or if it relies on entries `key_dir` didn't
+    /// keep. entries that have expired since are left out
+    pub fn read(cask: &str, key_dir: &KeyDir) -> Result<Option<Self>> {
+        let file = match File::open(format!("./{cask}/{SNAPSHOT}")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut input = CrcReader {
+            inner: BufReader::new(file),
+            hasher: Hasher::new(),
+        };
+
+        if &input.take::<4>()? != MAGIC {
+            bail!("not a keydir snapshot");
+        }
+        let [version, held] = input.take()?;
+        if version != VERSION {
+            bail!("unknown keydir snapshot version {version}");
+        }
+        if held == 1 {
+            if !key_dir.is_restored() {
+                bail!("the keydir didn't keep the entries the snapshot relies on");
+            }
+        } else {
+            key_dir.clear()?;
+        }
+
+        let active_id = input.u64()? as usize;
+        let offset = input.u64()?;
+        let files = (0..input.u32()?)
+            .map(|_| Ok((input.u64()? as usize, input.u64()?)))
+            .collect::<Result<_>>()?;
+
+        let now = now_millis();
+        loop {
+            let ksz = input.u32()?;
+            if ksz == END {
+                break;
+            }
+            let key = input.bytes(ksz as usize)?;
+            let entry = KeyDirEntry::new(
+                input.u64()? as usize,
+                input.u32()?,
+                input.u64()?,
+                input.u64()?,
+                input.u64()?,
+            );
+            if !entry.is_expired(now) {
+                key_dir.put(key, entry)?;
+            }
+        }
+
+        let crc = input.hasher.finalize();
+        let mut stored = [0; 4];
+        input.inner.read_exact(&mut stored)?;
+        if u32::from_be_bytes(stored) != crc {
+            bail!("keydir snapshot failed its crc");
+        }
+
+        Ok(Some(Self {
+            active_id,
+            offset,
+            files,
+        }))
+    }
+
+    /// checks that the snapshot still describes the cask: the files before its active
+    /// file are unchanged & the active file holds at least the data it covers
+    pub fn matches(&self, cask: &str) -> Result<bool> {
+        let mut files = vec![];
+        for file_id in data_file_ids(format!("./{cask}"))? {
+            let len = fs::metadata(format!("./{cask}/{file_id}"))?.len();
+            if file_id < self.active_id {
+                files.push((file_id, len));
+            } else if file_id == self.active_id && len < self.offset {
+                return Ok(false);
+            }
+        }
+
+        Ok(files == self.files && fs::exists(format!("./{cask}/{}", self.active_id))?)
+    }
+
+    /// removes the snapshot of the cask, if any
+    pub fn remove(cask: &str) -> Result<()> {
+        remove_if_exists(format!("./{cask}/{SNAPSHOT}"))?;
+        sync_dir(format!("./{cask}"))
+    }
+}
+
+fn write_entry<W: Write>(out: &mut CrcWriter<W>, k: &[u8], entry: &KeyDirEntry) -> Result<()> {
+    out.put(&(k.len() as u32).to_be_bytes())?;
+    out.put(k)?;
+    out.put(&(entry.file_id as u64).to_be_bytes())?;
+    out.put(&entry.val_sz.to_be_bytes())?;
+    out.put(&entry.val_pos.to_be_bytes())?;
+    out.put(&entry.tstamp.to_be_bytes())?;
+    out.put(&entry.expiry.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{KeyDirSnapshot, SNAPSHOT};
+    use crate::key_dir::{KeyDir, KeyDirEntry, KeyDirKind};
+
+    #[test]
+    fn test_keydir_snapshot() {
+        let cask = "keydir_snapshot_test";
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+        fs::create_dir_all(format!("./{cask}")).unwrap();
+        for (file_id, len) in [(0, 100), (1, 60)] {
+            fs::write(format!("./{cask}/{file_id}"), vec![0; len]).unwrap();
+        }
+
+        assert!(
+            KeyDirSnapshot::read(cask, &KeyDir::new())
+                .unwrap()
+                .is_none()
+        );
+
+        let key_dir = KeyDir::new();
+        key_dir
+            .put("abhi", KeyDirEntry::new(0, 4, 38, 1, 0))
+            .unwrap();
+        key_dir
+            .put("pads", KeyDirEntry::new(1, 4, 38, 2, 0))
+            .unwrap();
+        // already expired, so not worth persisting
+        key_dir
+            .put("swap", KeyDirEntry::new(1, 4, 75, 3, 1))
+            .unwrap();
+
+        let snapshot = KeyDirSnapshot::new(cask, 1, 42).unwrap();
+        assert_eq!(snapshot.files, [(0, 100)]);
+        snapshot.write(cask, &key_dir).unwrap();
+
+        let restored = KeyDir::new();
+        let read = KeyDirSnapshot::read(cask, &restored).unwrap().unwrap();
+        assert_eq!(read, snapshot);
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.get("pads").unwrap().unwrap().val_pos, 38);
+        assert!(read.matches(cask).unwrap());
+
+        // the active file may grow but older files must stay as they were
+        fs::write(format!("./{cask}/2"), b"").unwrap();
+        assert!(read.matches(cask).unwrap());
+        fs::write(format!("./{cask}/0"), vec![0; 90]).unwrap();
+        assert!(!read.matches(cask).unwrap());
+
+        // a flipped bit anywhere fails the crc
+        let path = format!("./{cask}/{SNAPSHOT}");
+        let mut data = fs::read(&path).unwrap();
+        data[30] ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(KeyDirSnapshot::read(cask, &KeyDir::new()).is_err());
+
+        KeyDirSnapshot::remove(cask).unwrap();
+        assert!(
+            KeyDirSnapshot::read(cask, &KeyDir::new())
+                .unwrap()
+                .is_none()
+        );
+
+        // a keydir on disk keeps its entries itself, so only the same keydir reopened
+        // can pick the snapshot up
+        let disk = KeyDirKind::Disk { cache_size: 0 };
+        let key_dir = KeyDir::open(disk, format!("./{cask}")).unwrap();
+        key_dir
+            .put("abhi", KeyDirEntry::new(0, 4, 38, 1, 0))
+            .unwrap();
+        snapshot.write(cask, &key_dir).unwrap();
+        drop(key_dir);
+
+        let reopened = KeyDir::open(disk, format!("./{cask}")).unwrap();
+        assert_eq!(
+            KeyDirSnapshot::read(cask, &reopened).unwrap(),
+            Some(snapshot)
+        );
+        assert_eq!(reopened.get("abhi").unwrap().unwrap().val_pos, 38);
+        // as if the db crashed
+        std::mem::forget(reopened);
+        let rebuilt = KeyDir::open(disk, format!("./{cask}")).unwrap();
+        assert!(KeyDirSnapshot::read(cask, &rebuilt).is_err());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+}
@@ -1,13 +1,33 @@
 pub mod app;
+pub mod background;
 pub mod builder;
+pub mod compact_key_dir;
+pub mod compaction;
 pub mod data_file_iter;
+pub mod disk_key_dir;
+pub mod durability;
+pub mod error;
+pub mod file_cache;
+pub mod format;
+pub mod frozen_key_dir;
 pub mod hint_file_iter;
 pub mod hydradb;
+pub mod iter;
 pub mod key_dir;
+pub mod keydir_snapshot;
 pub mod log_store;
+pub mod merge_journal;
+pub mod merge_output;
+pub mod merge_progress;
+pub mod multi_get;
 pub mod network;
+pub mod ordered_key_dir;
+pub mod rate_limiter;
 pub mod restore;
+pub mod scan;
+pub mod snapshot;
 pub mod utils;
+pub mod write_batch;
 
 use actix_web::HttpServer;
 use actix_web::middleware;
@@ -31,6 +51,7 @@ use openraft::StoredMembership;
 use openraft::storage::RaftStateMachine;
 use openraft::storage::Snapshot;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
 use std::io;
 use std::io::Cursor;
@@ -38,6 +59,7 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use tokio::sync::RwLock;
+use write_batch::WriteBatch;
 
 pub type LogStore = log_store::LogStore;
 
@@ -116,7 +138,7 @@ pub struct StateMachineData {
 impl StateMachineData {
     fn new(namespace: String) -> anyhow::Result<Self> {
         Ok(Self {
-            data: Arc::new(HydraDBBuilder::new().with_cask(namespace).build()?),
+            data: HydraDBBuilder::new().with_cask(namespace).build_shared()?,
             ..Default::default()
         })
     }
@@ -216,6 +238,11 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
 
         let mut sm = self.state_machine.write().await;
 
+        // all the writes in `entries` are applied to the db as a single batch
+        let mut batch = WriteBatch::new();
+        // whether keys touched by `batch` exist, since the db can't see them yet
+        let mut pending: HashMap<String, bool> = HashMap::new();
+
         for entry in entries {
             tracing::debug!(%entry.log_id, "replicate to sm");
 
@@ -225,27 +252,27 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
                 EntryPayload::Blank => res.push(Response::Blank { value: None }),
                 EntryPayload::Normal(ref req) => match req {
                     Request::Put { key, value } => {
-                        sm.data
-                            .put(key.clone(), value.clone())
-                            .map_err(|e| StorageError::IO {
-                                source: StorageIOError::new(
-                                    ErrorSubject::Store,
-                                    ErrorVerb::Write,
-                                    &io::Error::other(e),
-                                ),
-                            })?;
+                        batch.put(key.clone(), value.clone());
+                        pending.insert(key.clone(), true);
                         res.push(Response::Put {
                             prev_value: Some(value.clone()),
                         })
                     }
                     Request::Del { key } => {
-                        let existed = sm.data.del(key.clone()).map_err(|e| StorageError::IO {
-                            source: StorageIOError::new(
-                                ErrorSubject::Store,
-                                ErrorVerb::Write,
-                                &io::Error::other(e),
-                            ),
-                        })?;
+                        let existed = match pending.get(key) {
+                            Some(&existed) => existed,
+                            None => sm.data.has_key(key).map_err(|e| StorageError::IO {
+                                source: StorageIOError::new(
+                                    ErrorSubject::Store,
+                                    ErrorVerb::Read,
+                                    &io::Error::other(e),
+                                ),
+                            })?,
+                        };
+                        if existed {
+                            batch.del(key.clone());
+                            pending.insert(key.clone(), false);
+                        }
                         res.push(Response::Del { existed })
                     }
                 },
@@ -255,6 +282,15 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
                 }
             };
         }
+
+        sm.data.write(&batch).map_err(|e| StorageError::IO {
+            source: StorageIOError::new(
+                ErrorSubject::Store,
+                ErrorVerb::Write,
+                &io::Error::other(e),
+            ),
+        })?;
+
         Ok(res)
     }
 
@@ -38,6 +38,7 @@ impl Default for LogStore {
 }
 
 impl LogStore {
+    #[allow(clippy::result_large_err)]
     async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug>(
         &mut self,
         range: RB,
@@ -78,7 +79,7 @@ impl LogStore {
                 })
                 .unwrap();
 
-            entry.get_log_id().clone()
+            *entry.get_log_id()
         });
 
         let last_purged_log_id = self
@@ -93,7 +94,7 @@ impl LogStore {
         };
 
         let last = match last {
-            None => last_purged_log_id.clone(),
+            None => last_purged_log_id,
             Some(x) => Some(x),
         };
 
@@ -180,10 +181,18 @@ impl LogStore {
     {
         // Simple implementation that calls the flush-before-return `append_to_log`.
         for entry in entries {
-            self.log.insert(
-                u64::to_be_bytes(entry.get_log_id().index),
-                serde_json::to_vec(&entry).map_err(|e| StorageIOError::write_logs(&e))?,
-            );
+            self.log
+                .insert(
+                    u64::to_be_bytes(entry.get_log_id().index),
+                    serde_json::to_vec(&entry).map_err(|e| StorageIOError::write_logs(&e))?,
+                )
+                .map_err(|e| StorageError::IO {
+                    source: StorageIOError::new(
+                        ErrorSubject::Store,
+                        ErrorVerb::Write,
+                        &io::Error::other(e),
+                    ),
+                })?;
         }
         callback.log_io_completed(Ok(()));
 
@@ -205,17 +214,31 @@ impl LogStore {
             })?;
 
         for key in keys {
-            self.log.remove(&key);
+            self.log.remove(&key).map_err(|e| StorageError::IO {
+                source: StorageIOError::new(
+                    ErrorSubject::Store,
+                    ErrorVerb::Write,
+                    &io::Error::other(e),
+                ),
+            })?;
         }
 
         Ok(())
     }
 
     async fn purge(&mut self, log_id: LogId<NodeId>) -> Result<(), StorageError<NodeId>> {
-        self.log_state.insert(
-            b"last_purged_log_id",
-            serde_json::to_vec(&log_id).map_err(|e| StorageIOError::write_logs(&e))?,
-        );
+        self.log_state
+            .insert(
+                b"last_purged_log_id",
+                serde_json::to_vec(&log_id).map_err(|e| StorageIOError::write_logs(&e))?,
+            )
+            .map_err(|e| StorageError::IO {
+                source: StorageIOError::new(
+                    ErrorSubject::Store,
+                    ErrorVerb::Write,
+                    &io::Error::other(e),
+                ),
+            })?;
 
         {
             let keys = self
@@ -232,7 +255,13 @@ impl LogStore {
                 })?;
 
             for key in keys {
-                self.log.remove(&key);
+                self.log.remove(&key).map_err(|e| StorageError::IO {
+                    source: StorageIOError::new(
+                        ErrorSubject::Store,
+                        ErrorVerb::Write,
+                        &io::Error::other(e),
+                    ),
+                })?;
             }
         }
 
@@ -0,0 +1,192 @@
+use crate::utils::sync_dir;
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// name of the journal in a cask directory
+const JOURNAL: &str = "merge.journal";
+
+/// prefix of the files a merge writes its outputs to before publishing them
+const TEMP_PREFIX: &str = "merge-";
+
+/// path of the temp file the merged data file `file_id` is written to
+pub fn temp_data_path(cask: &str, file_id: usize) -> String {
+    format!("./{cask}/{TEMP_PREFIX}{file_id}")
+}
+
+/// path of the temp file the hint of the merged data file `file_id` is written to
+pub fn temp_hint_path(cask: &str, file_id: usize) -> String {
+    format!("./{cask}/{TEMP_PREFIX}{file_id}.hint")
+}
+
+/// the intent of a merge, persisted once its outputs are durable under temp names.
+///
+/// a journal on disk means that the merge must be rolled forward: its outputs get
+/// renamed into place & its inputs deleted. without one, any temp outputs are
+/// leftovers of a merge that never got that far and are simply removed
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergeJournal {
+    /// the data files being merged
+    pub inputs: Vec<usize>,
+    /// ids the merged data files are published under, if there was any live data
+    pub outputs: Vec<usize>,
+}
+
+impl MergeJournal {
+    /// durably writes the journal to the cask directory
+    pub fn write(&self, cask: &str) -> Result<()> {
+        let temp = format!("./{cask}/{JOURNAL}.tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+
+        fs::rename(temp, format!("./{cask}/{JOURNAL}"))?;
+        sync_dir(format!("./{cask}"))
+    }
+
+    /// reads the journal of an interrupted merge, if any
+    pub fn read(cask: &str) -> Result<Option<Self>> {
+        match fs::read(format!("./{cask}/{JOURNAL}")) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// publishes the outputs by renaming them over their final names. an output that
+    /// is already in place (no temp file left) is skipped, so this can be repeated
+    pub fn publish(&self, cask: &str) -> Result<()> {
+        for &file_id in &self.outputs {
+            let temp = temp_data_path(cask, file_id);
+            if Path::new(&temp).exists() {
+                // an output without a hint must not be read through the hint of the
+                // input it replaces
+                if !Path::new(&temp_hint_path(cask, file_id)).exists() {
+                    remove_if_exists(format!("./{cask}/{file_id}.hint"))?;
+                }
+                fs::rename(temp, format!("./{cask}/{file_id}"))?;
+            }
+
+            // the data file goes first so that a hint never describes an unmerged file
+            let temp = temp_hint_path(cask, file_id);
+            if Path::new(&temp).exists() {
+                fs::rename(temp, format!("./{cask}/{file_id}.hint"))?;
+            }
+        }
+
+        sync_dir(format!("./{cask}"))
+    }
+
+    /// deletes the inputs that weren't replaced by an output, along with their hints.
+    /// older files go first so that a crash midway never leaves an older record
+    /// behind without the newer one that shadows it
+    pub fn remove_inputs(&self, cask: &str) -> Result<()> {
+        for file_id in self.inputs.iter().filter(|id| !self.outputs.contains(id)) {
+            remove_if_exists(format!("./{cask}/{file_id}"))?;
+            remove_if_exists(format!("./{cask}/{file_id}.hint"))?;
+        }
+
+        sync_dir(format!("./{cask}"))
+    }
+
+    /// removes the journal once the merge is complete
+    pub fn remove(cask: &str) -> Result<()> {
+        remove_if_exists(format!("./{cask}/{JOURNAL}"))?;
+        sync_dir(format!("./{cask}"))
+    }
+
+    /// finishes a merge that was interrupted after writing its journal, or discards
+    /// the temp outputs of one that was interrupted before
+    pub fn recover(cask: &str) -> Result<()> {
+        if let Some(journal) = Self::read(cask)? {
+            info!(
+                "./{cask}: finishing interrupted merge of {:?}",
+                journal.inputs
+            );
+            journal.publish(cask)?;
+            journal.remove_inputs(cask)?;
+            Self::remove(cask)?;
+        }
+
+        for entry in fs::read_dir(format!("./{cask}"))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            // `temp` is where older versions wrote the merged data
+            if name.starts_with(TEMP_PREFIX) || name == "temp" || name == "merge.journal.tmp" {
+                warn!("./{cask}: removing {name} left behind by an interrupted merge");
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn remove_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{MergeJournal, temp_data_path, temp_hint_path};
+
+    #[test]
+    fn test_recover_rolls_forward() {
+        let cask = "merge_journal_roll_forward_test";
+        fs::create_dir_all(format!("./{cask}")).unwrap();
+        for file in ["0", "0.hint", "1", "2", "3"] {
+            fs::write(format!("./{cask}/{file}"), b"old").unwrap();
+        }
+        fs::write(temp_data_path(cask, 2), b"merged").unwrap();
+        fs::write(temp_hint_path(cask, 2), b"hint").unwrap();
+
+        let journal = MergeJournal {
+            inputs: vec![0, 1, 2],
+            outputs: vec![2],
+        };
+        journal.write(cask).unwrap();
+        assert_eq!(MergeJournal::read(cask).unwrap(), Some(journal));
+
+        MergeJournal::recover(cask).unwrap();
+        let mut files: Vec<_> = fs::read_dir(format!("./{cask}"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["2", "2.hint", "3"]);
+        assert_eq!(fs::read(format!("./{cask}/2")).unwrap(), b"merged");
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+
+    #[test]
+    fn test_recover_rolls_back() {
+        let cask = "merge_journal_roll_back_test";
+        fs::create_dir_all(format!("./{cask}")).unwrap();
+        for file in ["0", "1"] {
+            fs::write(format!("./{cask}/{file}"), b"old").unwrap();
+        }
+        fs::write(temp_data_path(cask, 0), b"half merged").unwrap();
+
+        MergeJournal::recover(cask).unwrap();
+        let mut files: Vec<_> = fs::read_dir(format!("./{cask}"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["0", "1"]);
+        assert_eq!(fs::read(format!("./{cask}/0")).unwrap(), b"old");
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::format::{FILE_HEADER_SZ, FormatVersion, RecordType, encode_record, file_header};
+use crate::merge_journal::{temp_data_path, temp_hint_path};
+use crate::merge_progress::MergeProgressTracker;
+use crate::rate_limiter::RateLimiter;
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+#[inline]
+fn to_hint_entry(tstamp: u64, expiry: u64, k: &[u8], v: &[u8], val_pos: u64) -> Vec<u8> {
+    // tstamp + expiry + ksz + vsz + val_pos + key
+    let mut o = Vec::with_capacity(8 + 8 + 4 + 4 + 8 + k.len());
+
+    let kl = k.len() as u32;
+    let vl = v.len() as u32;
+
+    o.extend_from_slice(&tstamp.to_be_bytes());
+    o.extend_from_slice(&expiry.to_be_bytes());
+    o.extend_from_slice(&kl.to_be_bytes());
+    o.extend_from_slice(&vl.to_be_bytes());
+    o.extend_from_slice(&val_pos.to_be_bytes());
+    o.extend_from_slice(k);
+    o
+}
+
+/// a merged data file being written under its temp name, along with its hint
+struct OutputFile {
+    file_id: usize,
+    data: BufWriter<File>,
+    hint: BufWriter<File>,
+    size: u64,
+    has_tombstones: bool,
+}
+
+impl OutputFile {
+    fn create(cask: &str, file_id: usize) -> Result<Self> {
+        let create = |path: String| -> Result<BufWriter<File>> {
+            let mut file = BufWriter::new(
+                File::options()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(path)?,
+            );
+            file.write_all(&file_header())?;
+            Ok(file)
+        };
+
+        Ok(Self {
+            file_id,
+            data: create(temp_data_path(cask, file_id))?,
+            hint: create(temp_hint_path(cask, file_id))?,
+            size: FILE_HEADER_SZ,
+            has_tombstones: false,
+        })
+    }
+
+    /// makes the file & its hint durable. the hint only describes puts, so a file
+    /// with tombstones has to be scanned on restore and doesn't keep one
+    fn finish(self, cask: &str) -> Result<()> {
+        for file in [self.data, self.hint] {
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+
+        if self.has_tombstones {
+            fs::remove_file(temp_hint_path(cask, self.file_id))?;
+        }
+        Ok(())
+    }
+}
+
+/// the files a merge writes the records it keeps to. the records are always written
+/// in the current format & a new file is started once one reaches the size limit
+pub(crate) struct MergeOutput<'a> {
+    cask: &'a str,
+    max_file_size_threshold: u64,
+    // ids the files can still take, the next one last
+    free_ids: Vec<usize>,
+    // takes a new id past the active file once `free_ids` run out
+    fresh_id: &'a dyn Fn() -> Result<usize>,
+    cur: Option<OutputFile>,
+    // ids of the finished files
+    done: Vec<usize>,
+    limiter: &'a RateLimiter,
+    progress: &'a MergeProgressTracker,
+}
+
+impl<'a> MergeOutput<'a> {
+    /// `ids` are the ids the files may take, in increasing order. the newest one is
+    /// used first. once they run out, every further file takes an id from `fresh_id`.
+    /// writes are throttled by `limiter` & counted in `progress`
+    pub fn new(
+        cask: &'a str,
+        max_file_size_threshold: u64,
+        ids: Vec<usize>,
+        fresh_id: &'a dyn Fn() -> Result<usize>,
+        limiter: &'a RateLimiter,
+        progress: &'a MergeProgressTracker,
+    ) -> Self {
+        Self {
+            cask,
+            max_file_size_threshold,
+            free_ids: ids,
+            fresh_id,
+            cur: None,
+            done: vec![],
+            limiter,
+            progress,
+        }
+    }
+
+    /// makes room for a record of `len` bytes, starting a new file if needed. returns
+    /// whether the new file took a fresh id
+    pub fn reserve(&mut self, len: u64) -> Result<bool> {
+        let full = self.cur.as_ref().is_none_or(|cur| {
+            cur.size > FILE_HEADER_SZ && cur.size + len >= self.max_file_size_threshold
+        });
+        if !full {
+            return Ok(false);
+        }
+
+        let (file_id, fresh) = match self.free_ids.pop() {
+            Some(file_id) => (file_id, false),
+            None => ((self.fresh_id)()?, true),
+        };
+        if let Some(cur) = self.cur.take() {
+            self.done.push(cur.file_id);
+            cur.finish(self.cask)?;
+        }
+        self.cur = Some(OutputFile::create(self.cask, file_id)?);
+        Ok(fresh)
+    }
+
+    /// appends a put along with its hint entry. returns the id of the file it went to
+    /// & the position of its value
+    pub fn put(&mut self, tstamp: u64, expiry: u64, k: &[u8], v: &[u8]) -> Result<(usize, u64)> {
+        let mut record = Vec::with_capacity(FormatVersion::CURRENT.header_sz() + k.len() + v.len());
+        encode_record(RecordType::Put, tstamp, expiry, k, v, &mut record);
+
+        self.reserve(record.len() as u64)?;
+        let size = self.cur.as_ref().unwrap().size;
+        let val_pos = size + (FormatVersion::CURRENT.header_sz() + k.len()) as u64;
+        let hint_entry = to_hint_entry(tstamp, expiry, k, v, val_pos);
+        let written = (record.len() + hint_entry.len()) as u64;
+        self.limiter.acquire(written);
+
+        let file = self.cur.as_mut().unwrap();
+        file.data.write_all(&record)?;
+        file.hint.write_all(&hint_entry)?;
+        file.size += record.len() as u64;
+        self.progress.written(written);
+
+        Ok((file.file_id, val_pos))
+    }
+
+    /// appends a tombstone for `k`. returns the id of the file it went to & its size
+    pub fn tombstone(&mut self, tstamp: u64, k: &[u8]) -> Result<(usize, u64)> {
+        let mut record = Vec::with_capacity(FormatVersion::CURRENT.header_sz() + k.len());
+        encode_record(RecordType::Delete, tstamp, 0, k, b"", &mut record);
+
+        self.reserve(record.len() as u64)?;
+        self.limiter.acquire(record.len() as u64);
+
+        let file = self.cur.as_mut().unwrap();
+        file.data.write_all(&record)?;
+        file.has_tombstones = true;
+        file.size += record.len() as u64;
+        self.progress.written(record.len() as u64);
+
+        Ok((file.file_id, record.len() as u64))
+    }
+
+    /// makes all the files durable & returns their ids, in increasing order
+    pub fn finish(mut self) -> Result<Vec<usize>> {
+        if let Some(cur) = self.cur.take() {
+            self.done.push(cur.file_id);
+            cur.finish(self.cask)?;
+        }
+
+        self.done.sort();
+        Ok(self.done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fs;
+
+    use super::MergeOutput;
+    use crate::data_file_iter::DataFileIterator;
+    use crate::hint_file_iter::HintFileIterator;
+    use crate::merge_journal::{temp_data_path, temp_hint_path};
+    use crate::merge_progress::MergeProgressTracker;
+    use crate::rate_limiter::RateLimiter;
+
+    #[test]
+    fn test_merge_output_rolls_over() {
+        let cask = "merge_output_test";
+        fs::create_dir_all(format!("./{cask}")).unwrap();
+
+        // a put is 29 + 4 + 4 bytes, so two of them fill up a file
+        let (limiter, progress) = (RateLimiter::default(), MergeProgressTracker::default());
+        let next_id = Cell::new(7);
+        let fresh_id = || Ok(next_id.replace(next_id.get() + 1));
+        let mut output = MergeOutput::new(cask, 100, vec![1, 4], &fresh_id, &limiter, &progress);
+        assert_eq!(output.put(1, 0, b"abhi", b"rust").unwrap(), (4, 5 + 29 + 4));
+        assert_eq!(output.put(1, 0, b"pads", b"java").unwrap().0, 4);
+        assert_eq!(output.put(1, 0, b"swap", b".net").unwrap(), (1, 5 + 29 + 4));
+        assert_eq!(output.tombstone(1, b"pooj").unwrap(), (1, 33));
+        // out of ids, so a fresh one is taken instead of overfilling 1
+        assert_eq!(output.put(1, 0, b"ashu", b"cpp.").unwrap(), (7, 5 + 29 + 4));
+        assert_eq!(output.finish().unwrap(), [1, 4, 7]);
+        // 4 puts with their hint entries & a tombstone
+        assert_eq!(progress.get().bytes_written, 4 * (37 + 36) + 33);
+
+        let keys = |id| -> Vec<Vec<u8>> {
+            DataFileIterator::new(temp_data_path(cask, id))
+                .unwrap()
+                .map(|e| e.unwrap().key)
+                .collect()
+        };
+        assert_eq!(keys(4), [b"abhi", b"pads"]);
+        assert_eq!(keys(1), [b"swap", b"pooj"]);
+        assert_eq!(keys(7), [b"ashu"]);
+        for id in [1, 4, 7] {
+            assert!(fs::metadata(temp_data_path(cask, id)).unwrap().len() <= 100);
+        }
+
+        assert_eq!(
+            HintFileIterator::new(temp_hint_path(cask, 4))
+                .unwrap()
+                .count(),
+            2
+        );
+        // 1 holds a tombstone so it has no hint
+        assert!(!fs::exists(temp_hint_path(cask, 1)).unwrap());
+
+        let _ = fs::remove_dir_all(format!("./{cask}"));
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// how far along the running merge is, or how the last one ended
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeProgress {
+    pub running: bool,
+    /// number of files being merged
+    pub files_total: usize,
+    /// number of those that have been read completely
+    pub files_done: usize,
+    /// size of the files being merged
+    pub bytes_total: u64,
+    pub bytes_read: u64,
+    /// bytes written to the merged data & hint files
+    pub bytes_written: u64,
+}
+
+impl MergeProgress {
+    /// share of the merged files' bytes read so far, from 0 to 1
+    pub fn fraction_done(&self) -> f64 {
+        if self.bytes_total == 0 {
+            1.0
+        } else {
+            self.bytes_read as f64 / self.bytes_total as f64
+        }
+    }
+}
+
+/// progress of merges, updated by the merging thread & read by anyone
+#[derive(Debug, Default)]
+pub(crate) struct MergeProgressTracker {
+    progress: Mutex<MergeProgress>,
+}
+
+/// marks the merge as no longer running when dropped, however it ended
+pub(crate) struct MergeRun<'a>(&'a MergeProgressTracker);
+
+impl Drop for MergeRun<'_> {
+    fn drop(&mut self) {
+        self.0.progress.lock().unwrap().running = false;
+    }
+}
+
+impl MergeProgressTracker {
+    pub fn start(&self, files_total: usize, bytes_total: u64) -> MergeRun<'_> {
+        *self.progress.lock().unwrap() = MergeProgress {
+            running: true,
+            files_total,
+            bytes_total,
+            ..Default::default()
+        };
+        MergeRun(self)
+    }
+
+    pub fn read(&self, bytes: u64) {
+        self.progress.lock().unwrap().bytes_read += bytes;
+    }
+
+    pub fn written(&self, bytes: u64) {
+        self.progress.lock().unwrap().bytes_written += bytes;
+    }
+
+    /// marks one more file as read. returns the progress so far
+    pub fn file_done(&self) -> MergeProgress {
+        let mut progress = self.progress.lock().unwrap();
+        progress.files_done += 1;
+        *progress
+    }
+
+    pub fn get(&self) -> MergeProgress {
+        *self.progress.lock().unwrap()
+    }
+}
@@ -0,0 +1,76 @@
+use std::ops::Range;
+
+/// records that lie at most this far apart are read at once
+pub(crate) const MAX_GAP: u64 = 4096;
+
+/// reads are combined up to this size
+pub(crate) const MAX_READ: u64 = 1 << 20;
+
+/// a read of a data file that covers one or more records
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Read {
+    pub span: Range<u64>,
+    /// indexes of the records it covers
+    pub records: Vec<usize>,
+}
+
+/// plans the reads of the records at `spans` of a data file, which must be sorted by
+/// where they start. records that lie close together are combined into one read
+pub(crate) fn plan_reads(spans: &[Range<u64>]) -> Vec<Read> {
+    let mut reads: Vec<Read> = vec![];
+    for (i, span) in spans.iter().enumerate() {
+        if let Some(read) = reads.last_mut()
+            && span.start <= read.span.end + MAX_GAP
+            && span.end.max(read.span.end) - read.span.start <= MAX_READ
+        {
+            read.span.end = read.span.end.max(span.end);
+            read.records.push(i);
+            continue;
+        }
+
+        reads.push(Read {
+            span: span.clone(),
+            records: vec![i],
+        });
+    }
+    reads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_GAP, MAX_READ, Read, plan_reads};
+
+    #[test]
+    fn test_plan_reads() {
+        assert!(plan_reads(&[]).is_empty());
+
+        let far = 100 + MAX_GAP * 2 + 1;
+        let reads = plan_reads(&[
+            0..40,
+            40..90,
+            // the same record asked for twice
+            40..90,
+            90 + MAX_GAP..100 + MAX_GAP,
+            far..far + 10,
+            // too big to be combined with the one before
+            far + 10..far + MAX_READ + 1,
+        ]);
+        assert_eq!(
+            reads,
+            [
+                Read {
+                    span: 0..100 + MAX_GAP,
+                    records: vec![0, 1, 2, 3],
+                },
+                Read {
+                    span: far..far + 10,
+                    records: vec![4],
+                },
+                Read {
+                    span: far + 10..far + MAX_READ + 1,
+                    records: vec![5],
+                },
+            ]
+        );
+    }
+}
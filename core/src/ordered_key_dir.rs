@@ -0,0 +1,99 @@
+use crate::key_dir::{KeyDirBackend, KeyDirEntry, KeyDirKind, KeyDirMemory, is_empty_range};
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::sync::RwLock;
+
+/// a keydir that keeps its keys sorted so that they can be scanned by range
+#[derive(Debug, Default)]
+pub(crate) struct OrderedKeyDir(RwLock<BTreeMap<Bytes, KeyDirEntry>>);
+
+impl KeyDirBackend for OrderedKeyDir {
+    fn get(&self, k: &[u8]) -> Result<Option<KeyDirEntry>> {
+        Ok(self.0.read().unwrap().get(k).cloned())
+    }
+
+    fn update(&self, k: &[u8], f: &mut dyn FnMut(&mut Option<KeyDirEntry>)) -> Result<()> {
+        let mut map = self.0.write().unwrap();
+        let mut cur = map.get(k).cloned();
+        let existed = cur.is_some();
+        f(&mut cur);
+        match cur {
+            Some(new) => match map.get_mut(k) {
+                Some(entry) => *entry = new,
+                None => {
+                    map.insert(Bytes::copy_from_slice(k), new);
+                }
+            },
+            None if existed => {
+                map.remove(k);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &KeyDirEntry)) -> Result<()> {
+        for (k, entry) in self.0.read().unwrap().iter() {
+            f(k, entry);
+        }
+        Ok(())
+    }
+
+    fn range(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        f: &mut dyn FnMut(&[u8], &KeyDirEntry) -> bool,
+    ) -> Result<()> {
+        // `BTreeMap::range` panics on a range that ends before it starts
+        if is_empty_range(start, end) {
+            return Ok(());
+        }
+
+        let map = self.0.read().unwrap();
+        let range = map.range::<[u8], _>((start, end));
+        let range: Box<dyn Iterator<Item = _>> = if reverse {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        };
+        for (k, entry) in range {
+            if !f(k, entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.0.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn memory(&self) -> KeyDirMemory {
+        let map = self.0.read().unwrap();
+        let key_bytes = map.keys().map(|k| k.len() as u64).sum();
+        // the nodes of a b-tree are about two thirds full
+        let table_bytes = (map.len() * size_of::<(Bytes, KeyDirEntry)>() * 3 / 2) as u64;
+
+        KeyDirMemory {
+            kind: KeyDirKind::Ordered,
+            keys: map.len(),
+            key_bytes,
+            table_bytes,
+            disk_bytes: 0,
+        }
+    }
+}
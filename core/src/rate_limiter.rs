@@ -0,0 +1,99 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    /// bytes per second, 0 if unlimited
+    rate: u64,
+    /// bytes that can be spent right away. goes negative when a caller takes more
+    /// than is available, which later callers pay off by waiting
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// a token bucket that limits i/o to a number of bytes per second, with bursts of up
+/// to a second's worth of bytes. a rate of 0 means no limit
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate: bytes_per_sec,
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// the current limit in bytes per second, 0 if unlimited
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// changes the limit. callers already waiting keep their old deadline
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = bytes_per_sec;
+        bucket.tokens = bucket.tokens.min(bytes_per_sec as f64);
+        bucket.last_refill = Instant::now();
+    }
+
+    /// blocks until `bytes` more bytes may be read or written
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            if bucket.rate == 0 {
+                return;
+            }
+
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * bucket.rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.rate as f64) - bytes as f64;
+            bucket.last_refill = now;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+        };
+
+        thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1000);
+
+        // a second's worth of bytes is available right away
+        let start = Instant::now();
+        limiter.acquire(1000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        limiter.acquire(300);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        limiter.set_rate(0);
+        let start = Instant::now();
+        limiter.acquire(u64::MAX);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(limiter.rate(), 0);
+    }
+}
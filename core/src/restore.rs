@@ -1,18 +1,183 @@
 use crate::data_file_iter::{DataFileEntry, DataFileIterator};
+use crate::error::HydraError;
+use crate::format::RecordType;
 use crate::hint_file_iter::{HintFileEntry, HintFileIterator};
 use crate::key_dir::{KeyDir, KeyDirEntry};
-use anyhow::Result;
+use crate::rate_limiter::RateLimiter;
+use crate::utils::now_millis;
+use anyhow::{Result, bail};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// where restored records go
+pub trait RestoreTarget {
+    /// puts `entry` for `key`, or deletes `key` if there is no entry (a tombstone)
+    fn apply(&mut self, key: Vec<u8>, entry: Option<KeyDirEntry>) -> Result<()>;
+}
+
+impl RestoreTarget for KeyDir {
+    fn apply(&mut self, key: Vec<u8>, entry: Option<KeyDirEntry>) -> Result<()> {
+        match entry {
+            // we either insert a key that doesn't exist or overwrite it
+            Some(entry) => self.put(key, entry)?,
+            None => self.del(&key)?,
+        };
+        Ok(())
+    }
+}
+
+/// the net changes a single file makes to the keydir: the last entry of every key it
+/// has a record of, or none if that record deletes the key
+#[derive(Debug, Default)]
+pub struct FileChanges(HashMap<Vec<u8>, Option<KeyDirEntry>>);
+
+impl RestoreTarget for FileChanges {
+    fn apply(&mut self, key: Vec<u8>, entry: Option<KeyDirEntry>) -> Result<()> {
+        self.0.insert(key, entry);
+        Ok(())
+    }
+}
+
+impl FileChanges {
+    /// applies the changes on top of `key_dir`
+    pub fn apply_to(self, key_dir: &mut KeyDir) -> Result<()> {
+        for (key, entry) in self.0 {
+            key_dir.apply(key, entry)?;
+        }
+        Ok(())
+    }
+}
 
 pub trait Restore {
+    /// replays the data file `file_id` into `key_dir` and returns the length of the valid
+    /// data in it. anything past that length is a torn or corrupt tail.
+    ///
+    /// files must be restored in increasing id order so that newer records win
     fn restore(
         &self,
         base_path: &str,
         cask: &str,
-        active_file_num: usize,
-        key_dir: &mut KeyDir,
-    ) -> Result<()>;
+        file_id: usize,
+        key_dir: &mut dyn RestoreTarget,
+    ) -> Result<u64>;
+}
+
+/// replays the data file `file_id` from the record at `from` (or its first one) into
+/// `key_dir`, throttled by `limiter`. records of a batch are only applied once its commit marker is seen &
+/// expired records count as deletes. returns the offset just past the last good record
+/// that isn't part of an uncommitted batch.
+///
+/// only the `active` file can be torn by a crash, so the scan stops at its first record
+/// that is short or fails its crc. such a record in an immutable file is corruption,
+/// as the records after it would be lost
+pub fn restore_data_file(
+    base_path: &str,
+    cask: &str,
+    file_id: usize,
+    from: u64,
+    active: bool,
+    limiter: &RateLimiter,
+    key_dir: &mut dyn RestoreTarget,
+) -> Result<u64> {
+    let path = format!("{base_path}/{cask}/{file_id}");
+    let mut file_iter = DataFileIterator::new(&path)?;
+    let version = file_iter.version();
+    if from > file_iter.data_start() {
+        file_iter.seek(from)?;
+    }
+    let mut valid_len = from.max(file_iter.data_start());
+    let now = now_millis();
+
+    // start offset & records of the batch being read, if any
+    let mut batch_start: Option<u64> = None;
+    let mut batch: Vec<(Vec<u8>, Option<KeyDirEntry>)> = vec![];
+
+    for entry in file_iter {
+        let corruption = || HydraError::Corruption {
+            file_id,
+            offset: valid_len,
+        };
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if active => {
+                warn!("{path}: dropping torn record at offset {valid_len}: {e}");
+                break;
+            }
+            Err(_) => return Err(corruption().into()),
+        };
+
+        if !entry.crc_matches(version) {
+            if !active {
+                return Err(corruption().into());
+            }
+            warn!("{path}: dropping record with bad crc at offset {valid_len}");
+            break;
+        }
+
+        let DataFileEntry {
+            rtype,
+            tstamp,
+            expiry,
+            ksz,
+            vsz,
+            key,
+            val_pos,
+            ..
+        } = entry;
+        let start = val_pos - (version.header_sz() as u64 + ksz as u64);
+        let end = val_pos + vsz as u64;
+        limiter.acquire(end - start);
+
+        let entry = match rtype {
+            RecordType::BatchBegin => {
+                if let Some(start) = batch_start {
+                    warn!("{path}: discarding uncommitted batch at offset {start}");
+                }
+                batch_start = Some(start);
+                batch.clear();
+                continue;
+            }
+            RecordType::BatchCommit => {
+                if batch_start.take().is_some() {
+                    for (key, entry) in batch.drain(..) {
+                        key_dir.apply(key, entry)?;
+                    }
+                }
+                valid_len = end;
+                continue;
+            }
+            // if entry is deleted, then we remove it from key_dir.
+            // an expired put hides older values of the key just like a delete
+            RecordType::Delete => None,
+            RecordType::Put => Some(KeyDirEntry::new(file_id, vsz, val_pos, tstamp, expiry))
+                .filter(|e| !e.is_expired(now)),
+        };
+
+        if batch_start.is_some() {
+            batch.push((key, entry));
+        } else {
+            key_dir.apply(key, entry)?;
+            valid_len = end;
+        }
+    }
+
+    if let Some(start) = batch_start {
+        warn!(
+            "{path}: discarding uncommitted batch of {} records at offset {start}",
+            batch.len()
+        );
+    }
+
+    Ok(valid_len)
 }
 
+/// restores a data file by scanning every record in it. a torn tail is cut off like
+/// that of the active file
 pub struct DataFileRestore;
 
 impl Restore for DataFileRestore {
@@ -20,29 +185,60 @@ impl Restore for DataFileRestore {
         &self,
         base_path: &str,
         cask: &str,
-        active_file_num: usize,
-        key_dir: &mut KeyDir,
-    ) -> Result<()> {
-        let file_iter = DataFileIterator::new(format!("{base_path}/{cask}/{active_file_num}"))?;
+        file_id: usize,
+        key_dir: &mut dyn RestoreTarget,
+    ) -> Result<u64> {
+        restore_data_file(
+            base_path,
+            cask,
+            file_id,
+            0,
+            true,
+            &RateLimiter::default(),
+            key_dir,
+        )
+    }
+}
+
+/// replays the data file `file_id` from its `{file_id}.hint` file into `key_dir`
+/// without reading the values, throttled by `limiter`. returns the length of the file
+pub fn restore_hint_file(
+    base_path: &str,
+    cask: &str,
+    file_id: usize,
+    limiter: &RateLimiter,
+    key_dir: &mut dyn RestoreTarget,
+) -> Result<u64> {
+    // a hint file only holds live records so there are no tombstones to apply.
+    // an expired record still hides any older put of its key
+    let path = format!("{base_path}/{cask}/{file_id}.hint");
+    let iter = HintFileIterator::new(&path)?;
+    let len = fs::metadata(format!("{base_path}/{cask}/{file_id}"))?.len();
+    let now = now_millis();
 
-        for DataFileEntry {
-            crc: _crc,
+    for entry in iter {
+        let HintFileEntry {
             tstamp,
-            ksz: _ksz,
+            expiry,
+            ksz,
             vsz,
             key,
-            val: _val,
             val_pos,
-        } in file_iter.flatten()
-        {
-            let key_dir_entry = KeyDirEntry::new(active_file_num, vsz, val_pos, tstamp);
-            key_dir.put(key, key_dir_entry);
+        } = entry?;
+        // hint entries carry no crc, but one can't point past the end of its file
+        if val_pos + vsz as u64 > len {
+            bail!("{path}: entry points past the end of the data file");
         }
-
-        Ok(())
+        // tstamp + expiry + ksz + vsz + val_pos + key
+        limiter.acquire(8 + 8 + 4 + 4 + 8 + ksz as u64);
+        let entry = KeyDirEntry::new(file_id, vsz, val_pos, tstamp, expiry);
+        key_dir.apply(key, Some(entry).filter(|e| !e.is_expired(now)))?;
     }
+
+    Ok(len)
 }
 
+/// restores a merged data file from its `{file_id}.hint` file without reading the values
 pub struct HintFileRestore;
 
 impl Restore for HintFileRestore {
@@ -50,50 +246,159 @@ impl Restore for HintFileRestore {
         &self,
         base_path: &str,
         cask: &str,
-        active_file_num: usize,
-        key_dir: &mut KeyDir,
-    ) -> Result<()> {
-        // if there's a hint file, there's an active file as well.
-        // in addition to hint file, process the active file as well.
+        file_id: usize,
+        key_dir: &mut dyn RestoreTarget,
+    ) -> Result<u64> {
+        restore_hint_file(base_path, cask, file_id, &RateLimiter::default(), key_dir)
+    }
+}
 
-        // 1. process hint file
-        let iter = HintFileIterator::new(format!("{base_path}/{cask}/hint"))?;
+/// a file to restore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreJob {
+    pub file_id: usize,
+    /// offset of the first record to replay, 0 for the whole file
+    pub from: u64,
+    /// whether to read the file's hint instead of the file itself
+    pub use_hint: bool,
+    /// whether it's the active file, whose torn tail is cut off instead of failing
+    pub active: bool,
+}
 
-        for HintFileEntry {
-            tstamp,
-            ksz: _k,
-            vsz,
-            key,
-            val_pos,
-        } in iter.flatten()
-        {
-            let entry = KeyDirEntry::new(active_file_num - 1, vsz, val_pos, tstamp);
-            key_dir.put(key, entry);
+/// how a restore went
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RestoreStats {
+    /// number of worker threads
+    pub threads: usize,
+    /// number of data & hint files read
+    pub files: usize,
+    /// bytes read from them
+    pub bytes: u64,
+    /// keys in the restored keydir
+    pub keys: usize,
+    pub elapsed: Duration,
+}
+
+impl RestoreStats {
+    /// restore throughput in bytes per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.bytes as f64 / secs
         }
+    }
+}
 
-        // 2. process data file
-        let file_iter = DataFileIterator::new(format!("{base_path}/{cask}/{active_file_num}"))?;
+/// replays a single job into `target`, throttled by `limiter`. returns the length of
+/// the valid data in the file & the number of bytes read
+fn restore_job(
+    base_path: &str,
+    cask: &str,
+    job: &RestoreJob,
+    limiter: &RateLimiter,
+    target: &mut dyn RestoreTarget,
+) -> Result<(u64, u64)> {
+    if job.use_hint {
+        let bytes = fs::metadata(format!("{base_path}/{cask}/{}.hint", job.file_id))?.len();
+        match restore_hint_file(base_path, cask, job.file_id, limiter, target) {
+            Ok(len) => return Ok((len, bytes)),
+            // the entries applied so far all lie in the file itself, so scanning it
+            // on top of them leaves the same changes as scanning it alone
+            Err(e) => warn!(
+                "{base_path}/{cask}/{}.hint: falling back to scanning the data file: {e}",
+                job.file_id
+            ),
+        }
+        let len = restore_data_file(base_path, cask, job.file_id, 0, job.active, limiter, target)?;
+        Ok((len, bytes + len))
+    } else {
+        let len = restore_data_file(
+            base_path,
+            cask,
+            job.file_id,
+            job.from,
+            job.active,
+            limiter,
+            target,
+        )?;
+        Ok((len, len.saturating_sub(job.from)))
+    }
+}
 
-        for DataFileEntry {
-            crc: _crc,
-            tstamp,
-            ksz: _ksz,
-            vsz,
-            key,
-            val,
-            val_pos,
-        } in file_iter.flatten()
-        {
-            // if entry is deleted, then we remove it from key_dir
-            if str::from_utf8(&val).unwrap() == "TOMBSTONE" {
-                key_dir.del(&key);
-            } else {
-                // we either insert a key that doesn't exist or overwrite it
-                let entry = KeyDirEntry::new(active_file_num, vsz, val_pos, tstamp);
-                key_dir.put(key, entry);
-            }
+/// restores the files of `jobs`, in increasing id order, into `key_dir` on up to
+/// `threads` workers. the files are parsed in parallel into their net changes, which
+/// are then applied in id order so that the keydir ends up just like after restoring
+/// the files one after another. their reads share the rate of `limiter`. returns the
+/// length of the valid data in every file
+pub fn restore_files(
+    base_path: &str,
+    cask: &str,
+    jobs: &[RestoreJob],
+    threads: usize,
+    limiter: &RateLimiter,
+    key_dir: &mut KeyDir,
+) -> Result<(Vec<u64>, RestoreStats)> {
+    let start = Instant::now();
+    let threads = threads.clamp(1, jobs.len().max(1));
+    let mut lens = Vec::with_capacity(jobs.len());
+    let mut bytes = 0;
+
+    if threads == 1 {
+        for job in jobs {
+            let (len, read) = restore_job(base_path, cask, job, limiter, key_dir)?;
+            lens.push(len);
+            bytes += read;
         }
+    } else {
+        let next = &AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel();
 
-        Ok(())
+        thread::scope(|s| -> Result<()> {
+            for _ in 0..threads {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    while let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let mut changes = FileChanges::default();
+                        let res = restore_job(base_path, cask, job, limiter, &mut changes)
+                            .map(|(len, read)| (len, read, changes));
+                        let failed = res.is_err();
+                        // the receiver is gone once another job failed
+                        if tx.send((job.file_id, res)).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            // jobs finish in any order but must be applied in id order
+            let mut done = BTreeMap::new();
+            let mut pending = jobs.iter();
+            let mut waiting_for = pending.next();
+            for (file_id, res) in rx {
+                done.insert(file_id, res);
+                while let Some(job) = waiting_for
+                    && let Some(res) = done.remove(&job.file_id)
+                {
+                    let (len, read, changes) = res?;
+                    changes.apply_to(key_dir)?;
+                    lens.push(len);
+                    bytes += read;
+                    waiting_for = pending.next();
+                }
+            }
+            Ok(())
+        })?;
     }
+
+    let stats = RestoreStats {
+        threads,
+        files: jobs.len(),
+        bytes,
+        keys: key_dir.len(),
+        elapsed: start.elapsed(),
+    };
+    Ok((lens, stats))
 }
@@ -0,0 +1,119 @@
+use crate::hydradb::HydraDB;
+use crate::key_dir::KeyDirEntry;
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+/// how many keys a scan looks up in the keydir at a time
+const BATCH: usize = 128;
+
+/// an iterator over a range of keys & their values, in key order or in reverse.
+///
+/// keys are looked up a batch at a time & their values are read as they are reached,
+/// so the keydir is never locked for long. a write made meanwhile may or may not be
+/// seen, but every key that exists throughout the scan is yielded exactly once
+#[derive(Debug)]
+pub struct Scan<'a> {
+    db: &'a HydraDB,
+    start: Bound<Bytes>,
+    end: Bound<Bytes>,
+    reverse: bool,
+    remaining: usize,
+    batch: VecDeque<(Bytes, KeyDirEntry)>,
+    exhausted: bool,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(db: &'a HydraDB, start: Bound<Bytes>, end: Bound<Bytes>) -> Self {
+        Self {
+            db,
+            start,
+            end,
+            reverse: false,
+            remaining: usize::MAX,
+            batch: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// scans the keys starting with `prefix`
+    pub(crate) fn prefix(db: &'a HydraDB, prefix: &[u8]) -> Self {
+        // the keys with the prefix sort before the prefix cut after its last byte below
+        // 0xff, with that byte incremented. there is no such bound if every byte is 0xff
+        let end = match prefix.iter().rposition(|&b| b != u8::MAX) {
+            Some(i) => {
+                let mut end = prefix[..=i].to_vec();
+                end[i] += 1;
+                Bound::Excluded(end.into())
+            }
+            None => Bound::Unbounded,
+        };
+        Self::new(db, Bound::Included(Bytes::copy_from_slice(prefix)), end)
+    }
+
+    /// yields at most `limit` keys
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.remaining = limit;
+        self
+    }
+
+    /// yields the keys in descending order
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// looks up the next batch of keys & moves past them
+    fn fill(&mut self) -> Result<()> {
+        let want = BATCH.min(self.remaining);
+        let batch = self.db.key_dir().range(
+            self.start.as_ref().map(|k| &k[..]),
+            self.end.as_ref().map(|k| &k[..]),
+            self.reverse,
+            want,
+        )?;
+        self.exhausted = batch.len() < want;
+
+        if let Some((last, _)) = batch.last() {
+            let cursor = Bound::Excluded(last.clone());
+            if self.reverse {
+                self.end = cursor;
+            } else {
+                self.start = cursor;
+            }
+        }
+        self.batch.extend(batch);
+        Ok(())
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            let Some((k, entry)) = self.batch.pop_front() else {
+                if self.exhausted {
+                    return None;
+                }
+                if let Err(e) = self.fill() {
+                    self.exhausted = true;
+                    return Some(Err(e));
+                }
+                continue;
+            };
+
+            match self.db.read_entry(&k, &entry) {
+                Ok(Some(val)) => {
+                    self.remaining -= 1;
+                    return Some(Ok((k, val)));
+                }
+                // expired or deleted since the batch was looked up
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
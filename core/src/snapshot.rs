@@ -0,0 +1,94 @@
+use crate::file_cache::DataFile;
+use crate::frozen_key_dir::{FrozenIter, FrozenKeyDir};
+use crate::hydradb::read_record;
+use crate::key_dir::KeyDirEntry;
+use crate::utils::now_millis;
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// a consistent, read-only view of a db as it was when the snapshot was taken. writes
+/// & merges made since don't show through it.
+///
+/// the snapshot holds open the data files that were in the cask when it was taken. a
+/// merge that replaces or removes one of them only unlinks it, so its data stays readable & its space is
+/// given back once the last snapshot holding it is dropped.
+///
+/// every key written while a snapshot is around leaves its old entry with it, so a
+/// snapshot kept for long over a busy db grows with the keys written. drop it once done
+#[derive(Debug)]
+pub struct Snapshot<'a> {
+    key_dir: FrozenKeyDir<'a>,
+    files: HashMap<usize, Arc<DataFile>>,
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn new(key_dir: FrozenKeyDir<'a>, files: HashMap<usize, Arc<DataFile>>) -> Self {
+        Self { key_dir, files }
+    }
+
+    /// gets the value, if present, that the given key `k` had
+    pub fn get(&self, k: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
+        let k = k.as_ref();
+        match self.key_dir.get(k)? {
+            Some(entry) if !entry.is_expired(now_millis()) => self.read(k, &entry).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// iterates lazily over the keys that the snapshot holds & haven't expired, along
+    /// with their values
+    pub fn iter(&self) -> SnapshotIter<'_> {
+        SnapshotIter {
+            snapshot: self,
+            entries: self.key_dir.iter(),
+        }
+    }
+
+    /// iterates lazily over the keys that the snapshot holds & haven't expired
+    pub fn keys(&self) -> impl Iterator<Item = Result<Bytes>> + '_ {
+        let now = now_millis();
+        self.key_dir
+            .iter()
+            .filter(move |res| !matches!(res, Ok((_, entry)) if entry.is_expired(now)))
+            .map(|res| res.map(|(k, _)| k))
+    }
+
+    /// number of data files the snapshot holds open
+    pub fn pinned_files(&self) -> usize {
+        self.files.len()
+    }
+
+    fn read(&self, k: &[u8], entry: &KeyDirEntry) -> Result<Bytes> {
+        let data_file = self
+            .files
+            .get(&entry.file_id)
+            .ok_or_else(|| anyhow!("data file {} isn't in the snapshot", entry.file_id))?;
+        read_record(data_file, k, entry)
+    }
+}
+
+/// a lazy iterator over the keys of a snapshot & their values
+#[derive(Debug)]
+pub struct SnapshotIter<'a> {
+    snapshot: &'a Snapshot<'a>,
+    entries: FrozenIter<'a>,
+}
+
+impl Iterator for SnapshotIter<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = now_millis();
+        let (k, entry) = match self
+            .entries
+            .by_ref()
+            .find(|res| !matches!(res, Ok((_, entry)) if entry.is_expired(now)))?
+        {
+            Ok(found) => found,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(self.snapshot.read(&k, &entry).map(|val| (k, val)))
+    }
+}
@@ -1,11 +1,47 @@
+use anyhow::Result;
 use crc32fast::Hasher;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn calc_crc(tstamp: u32, key_sz: u32, val_sz: u32, k: &[u8], v: &[u8]) -> u32 {
+/// computes the crc of a record from its header (without the crc field), key & value
+pub fn calc_crc(header: &[u8], k: &[u8], v: &[u8]) -> u32 {
     let mut hasher = Hasher::new();
-    hasher.update(&tstamp.to_be_bytes());
-    hasher.update(&key_sz.to_be_bytes());
-    hasher.update(&val_sz.to_be_bytes());
+    hasher.update(header);
     hasher.update(k);
     hasher.update(v);
     hasher.finalize()
 }
+
+/// returns the current time in millis since the unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// fsyncs the directory `dir` so that renames & deletions in it are durable
+pub fn sync_dir(dir: impl AsRef<Path>) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// returns the ids of all the data files in the cask directory `dir`, in increasing order.
+/// hint files and temp files are skipped
+pub fn data_file_ids(dir: impl AsRef<Path>) -> Result<Vec<usize>> {
+    let mut ids = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_file()
+            && let Some(name) = path.file_name().and_then(|name| name.to_str())
+            && let Ok(id) = name.parse::<usize>()
+        {
+            ids.push(id);
+        }
+    }
+
+    ids.sort();
+    Ok(ids)
+}
@@ -0,0 +1,48 @@
+use bytes::Bytes;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Bytes, Bytes),
+    Del(Bytes),
+}
+
+/// a group of puts & deletes that are applied atomically by `HydraDB::write`.
+/// either all of them survive a crash or none of them do
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self { ops: vec![] }
+    }
+
+    /// queues a put of the given key-value pair
+    pub fn put(&mut self, k: impl Into<Bytes>, v: impl Into<Bytes>) -> &mut Self {
+        self.ops.push(BatchOp::Put(k.into(), v.into()));
+        self
+    }
+
+    /// queues a delete of the given key
+    pub fn del(&mut self, k: impl Into<Bytes>) -> &mut Self {
+        self.ops.push(BatchOp::Del(k.into()));
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}